@@ -1,138 +1,755 @@
 // © Zach Nielsen 2024
 
-use std::fs::File;
-use std::path::PathBuf;
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::io::{self, IsTerminal, Write};
 use std::cmp::max;
+use std::sync::OnceLock;
+use std::hash::{BuildHasher, Hasher};
 
-use chrono::{self, DateTime, Datelike, Local, naive::NaiveDate, TimeZone, Timelike};
+use chrono::{self, DateTime, FixedOffset, Local, LocalResult, naive::NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use clap::{Parser, Subcommand};
-use crossterm::{self, event::KeyCode};
-use dirs;
+use crossterm::{self, event::KeyCode, style::Stylize};
 use regex::RegexBuilder;
 use serde::{Deserialize, Serialize};
-use serde_json;
 
-const PATH: &str = "dotfiles/not_quite_dotfiles/watches";
+// Database location, resolved once at startup. See `Cli::db` for the resolution order.
+static DB_PATH: OnceLock<PathBuf> = OnceLock::new();
+// Where the database lived, relative to the home directory, before its path could be configured
+const LEGACY_DB_PATH: &str = "dotfiles/not_quite_dotfiles/watches";
+// User preferences, loaded once at startup from `get_config_path()`
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Version of the on-disk database format. When changing it, add a step to `MIGRATIONS` that
+// upgrades files written with the previous version.
+const SCHEMA_VERSION: u32 = 5;
+// Length of a month for rates of change, matching `RateUnit::Month`
+const DAYS_PER_MONTH: f64 = 365.0 / 12.0;
+// Entry `n` upgrades a database from schema version `n` to `n + 1`
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "wrap the list of watches in a versioned envelope",
+        apply: migrate_v0_envelope,
+    },
+    Migration {
+        description: "give every watch a unique ID",
+        apply: migrate_v1_watch_ids,
+    },
+    Migration {
+        description: "store readings in UTC along with the offset the watch was set to",
+        apply: migrate_v2_utc_readings,
+    },
+    Migration {
+        description: "split movements into kinds; Mechanical becomes automatic (change with `wd edit -m`)",
+        apply: migrate_v3_movement_kinds,
+    },
+    Migration {
+        description: "store measured rates unit-free instead of in the movement's unit",
+        apply: migrate_v4_unit_free_rates,
+    },
+];
 
 fn main() {
     let args = Cli::parse();
+    // `wd config` has to work with a broken config, since it's how the config gets fixed
+    let config = match args.command {
+        Commands::Config { .. } => try_load_config().unwrap_or_else(|e| {
+            println!("Ignoring invalid config, using the defaults: {}", e);
+            Config::default()
+        }),
+        _ => load_config(),
+    };
+    CONFIG.set(config).unwrap();
+    DB_PATH.set(resolve_db_path(args.db)).unwrap();
+    // Held until exit so a command's whole load/modify/save cycle is exclusive
+    let _lock = args.command.writes_db().then(|| lock_db(args.no_wait));
+    let all = args.all;
     match args.command {
-        Commands::New { name, movement } => handle_new(WatchBuilder{ name, movement }),
-        Commands::Start { name }         => handle_start(name.join(" ")),
-        Commands::End { name }           => handle_end(name.join(" ")),
-        Commands::Ls { search }          => handle_ls(search.join(" ")),
-        Commands::Recalculate { search } => handle_recalculate(search.join(" ")),
-        Commands::Log { name }           => handle_log(name.join(" ")),
-        Commands::Print { }              => handle_print(),
+        Commands::Init { }               => handle_init(),
+        Commands::New { name, movement, watch_tz } => handle_new(WatchBuilder{ name, movement, watch_tz }),
+        Commands::Start { reading, from_set, name } => handle_start(name.join(" "), reading, from_set, all),
+        Commands::End { reading, units, name } => handle_end(name.join(" "), reading, units, all),
+        Commands::Check { reading, name } => handle_check(name.join(" "), reading, all),
+        Commands::Set { offset, at, yes, name } => handle_set(name.join(" "), offset, at, yes, all),
+        Commands::Trend { min, max, name } => handle_trend(name.join(" "), min, max, all),
+        Commands::SetAdvice { until, target, agg, name } =>
+            handle_set_advice(name.join(" "), until, target, agg, all),
+        Commands::Ls { ids, agg, units, search } => handle_ls(search.join(" "), ids, agg, units, all),
+        Commands::Recalculate { search }  => handle_recalculate(search.join(" "), all),
+        Commands::Log { name }            => handle_log(name.join(" "), all),
+        Commands::Print { agg, units }    => handle_print(agg, units, all),
+        Commands::Rename { to, name }     => handle_rename(name.join(" "), to, all),
+        Commands::Edit { movement, unit, spec, no_spec, watch_tz, no_watch_tz, name } => {
+            let spec = if no_spec { Some(None) } else { spec.map(Some) };
+            let watch_tz = if no_watch_tz { Some(None) } else { watch_tz.map(Some) };
+            handle_edit(name.join(" "), movement, unit, spec, watch_tz, all)
+        },
+        Commands::Rm { yes, name }        => handle_rm(name.join(" "), yes, all),
+        Commands::Archive { name }        => handle_archive(name.join(" "), true),
+        Commands::Unarchive { name }      => handle_archive(name.join(" "), false),
+        Commands::Config { action }       => handle_config(action),
+        Commands::Calibrate { trials }    => handle_calibrate(trials as usize),
+        Commands::PositionTest { reading, early, restart, report, yes, name } =>
+            handle_position_test(name.join(" "), reading, early, restart, report, yes, all),
+        Commands::Migrate { dry_run }     => handle_migrate(dry_run),
     }
 }
+fn handle_init() {
+    let path = get_path();
+    if path.exists() {
+        println!("Database already exists at [{:?}]", path);
+        return;
+    }
+    save_file(Vec::new());
+    println!("Created empty database at [{:?}]", path);
+}
 fn handle_new(wb: WatchBuilder) {
     let mut watch = Watch::new();
 
-    watch.name = if wb.name.is_none() {
+    watch.name = if let Some(name) = wb.name {
+        name
+    } else {
         print!("Watch Name: ");
         io::stdout().flush().unwrap();
         let mut input = String::new();
         io::stdin().read_line(&mut input)
             .expect("Failed to read line");
         input.trim().to_owned()
-    } else {
-        wb.name.unwrap()
     };
-    watch.movement = if wb.movement.is_none() {
+    watch.movement = if let Some(movement) = wb.movement {
+        movement
+    } else {
+        let movements = <Movement as clap::ValueEnum>::value_variants();
         let mut mvt = None;
         while mvt.is_none() {
             println!("Watch type");
-            println!("  [1]: Quartz");
-            println!("  [2]: Mechanical");
-            println!("  [3]: Smart");
-            print!  ("Enter (1/2/3): ");
+            for (idx, m) in movements.iter().enumerate() {
+                println!("  [{}]: {}", idx + 1, m.to_str());
+            }
+            print!  ("Enter (1-{}): ", movements.len());
             io::stdout().flush().unwrap();
             let mut input = String::new();
             io::stdin().read_line(&mut input)
                 .expect("Failed to read line");
-            mvt = match input.trim() {
-                "1" => Some(Movement::Quartz),
-                "2" => Some(Movement::Mechanical),
-                "3" => Some(Movement::Smart),
-                _ => None,
-            };
+            mvt = input.trim().parse::<usize>().ok()
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|idx| movements.get(idx))
+                .cloned();
         }
         mvt.unwrap()
-    } else {
-        wb.movement.unwrap()
     };
+    watch.watch_tz = wb.watch_tz.map(|tz| tz.name().to_owned());
 
+    if load_file().iter().any(|w| w.name == watch.name) {
+        println!("A watch named [{}] already exists. Use @<id> to tell them apart (see `wd ls --ids`).", watch.name);
+    }
     watch.save();
+    println!("Created [{}] with ID @{}", watch.name, watch.id);
 }
-fn handle_start(name: String) {
-    let mut w = get_matching_watch(name);
+fn handle_start(name: String, reading: Reading, from_set: bool, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    w.require_no_active_leg();
     println!("Starting measure for [{}]", w.name);
     if let Some(start) = w.measure_start() {
         println!("Overwriting start time: {:?}", start);
     }
-    let now = get_00_time();
-    let watch_time = get_watch_time_from_real_time(now);
+    let start = if from_set {
+        match w.sets.last() {
+            Some(set) => {
+                println!("Starting from when it was set ({}) at {}", format_offset(set.offset_secs()),
+                    set.watch_reading().format("%Y-%m-%d %H:%M:%S"));
+                set.clone()
+            },
+            None => {
+                println!("[{}] has never been set. Record it with `wd set`.", w.name);
+                std::process::exit(1);
+            },
+        }
+    } else {
+        get_watch_time_pair(reading, &w)
+    };
 
     w.measures.push(Measure {
-        measure_start: Some( WatchTimePair {
-            real_time: now,
-            watch_time,
-        }),
+        measure_start: Some(start),
+        checks: Vec::new(),
         measure_end: None,
-        drift: None,
+        rate: None,
+        rate_sd: None,
+        leg: None,
     });
     w.save()
 }
-fn handle_end(name: String) {
-    let mut w = get_matching_watch(name);
-    if w.measures.last().unwrap().measure_end.is_some() {
-        println!("End measure update for [{}]", w.name);
-        println!("Updating measure:\n{}", w.measures.last().unwrap());
-    } else {
-        println!("Ending measure for [{}]", w.name);
+fn handle_end(name: String, reading: Reading, units: Option<RateUnit>, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    w.require_no_active_leg();
+    match w.measures.last() {
+        // A finished position test leg isn't a measure that `end` can update
+        None | Some(Measure { leg: Some(_), .. }) => {
+            println!("No measure to end for [{}]. Start one with `wd start`.", w.name);
+            std::process::exit(1);
+        },
+        Some(m) if m.measure_end.is_some() => {
+            println!("End measure update for [{}]", w.name);
+            println!("Updating measure:\n{}", m.in_unit(units.unwrap_or(w.rate_unit())));
+        },
+        Some(_) => println!("Ending measure for [{}]", w.name),
     }
-    let now = get_00_time();
-    let watch_time = get_watch_time_from_real_time(now);
+    let end = get_watch_time_pair(reading, &w);
 
-    w.measures.last_mut().unwrap().measure_end = Some( WatchTimePair {
-        real_time: now,
-        watch_time,
-    });
+    let m = w.measures.last_mut().unwrap();
+    // An update keeps the previous end as an intermediate reading, so it still counts in the fit
+    if let Some(previous) = m.measure_end.replace(end) {
+        m.add_check(previous);
+    }
     w.update_running();
+    let m = w.measures.last().unwrap();
+    if m.rate.is_none() {
+        println!("The readings are too close together to work out a rate. Measure wasn't ended.");
+        std::process::exit(1);
+    }
     w.save();
 
-    let (val, units) = w.last_complete_measure().unwrap().measure_duration_and_units();
+    let m = w.measures.last().unwrap();
+    let (val, units_str) = m.measure_duration_and_units();
 
     println!("\n");
-    println!("Watch is running at {:+} seconds per {}, measured over {} {}",
-        w.drift().unwrap(), w.movement.unit_str(), val, units);
-    println!("")
+    println!("Watch is running at {}, measured over {} {}",
+        format_drift(m.rate.unwrap(), m.rate_sd, units.unwrap_or(w.rate_unit())), val, units_str);
+    print_fit(m);
+    println!()
 }
-fn handle_ls(query: String) {
-    let watches = get_matching_watches(&query);
+fn handle_check(name: String, reading: Reading, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    w.require_no_active_leg();
+    if w.measures.last().is_none_or(|m| m.measure_end.is_some()) {
+        println!("No active measure for [{}]. Start one with `wd start`.", w.name);
+        std::process::exit(1);
+    }
+    println!("Adding a reading to the measure for [{}]", w.name);
+    let check = get_watch_time_pair(reading, &w);
+
+    w.measures.last_mut().unwrap().add_check(check);
+    w.save();
+
+    let m = w.measures.last().unwrap();
+    let readings = m.readings();
+    let (val, units) = get_measure_duration_and_units(readings[0].real_time, readings[readings.len() - 1].real_time);
+
+    println!("\n");
+    match m.fit() {
+        Some(fit) => {
+            println!("So far the watch is running at {}, over {} {}",
+                format_drift(fit.slope, Some(m.rate_uncertainty(&fit)), w.rate_unit()), val, units);
+            print_fit(m);
+        },
+        None => println!("Not enough time has passed to estimate a rate yet"),
+    }
+    println!()
+}
+fn handle_set(name: String, offset: f64, at: Option<RealTime>, yes: bool, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    w.require_no_active_leg();
+    // Moving the hands breaks a running measure, so it can't be ended with a meaningful rate
+    abandon_active_measure(&mut w, "Setting it", yes);
+    let system_tz = get_system_tz();
+    let (real_time, clock) = match at {
+        Some(RealTime::At(t)) => (t, (None, None)),
+        Some(RealTime::Now(t)) => (t, check_clock()),
+        None => {
+            require_terminal("--at now");
+            (wait_for_enter("Press [Enter] as you push the crown in ", w.tz().or(system_tz)), check_clock())
+        },
+    };
+    let watch_offset = match w.tz().or(system_tz) {
+        Some(tz) => real_time.with_timezone(&tz).offset().fix().local_minus_utc(),
+        None => real_time.with_timezone(&Local).offset().fix().local_minus_utc(),
+    };
+    // The hands were put exactly where they should be, so the offset is as good as the reaction time
+    w.sets.push(WatchTimePair {
+        watch_time: real_time + chrono::TimeDelta::milliseconds((offset * 1000.0).round() as i64),
+        real_time,
+        watch_offset,
+        system_tz: system_tz.map(|tz| tz.name().to_owned()),
+        precision: TimePrecision::Second,
+        clock_offset: clock.0,
+        clock_delay: clock.1,
+    });
+    w.save();
+    println!("Recorded [{}] as set {}", w.name, format_offset(offset));
+}
+// Drop a running worn measure that `doing` would spoil, after confirming unless `yes`
+fn abandon_active_measure(w: &mut Watch, doing: &str, yes: bool) {
+    let Some(start) = w.measures.last()
+        .filter(|m| m.leg.is_none() && m.measure_end.is_none())
+        .and_then(|m| m.measure_start.as_ref()) else { return };
+    if !yes {
+        print!("[{}] has a measure running since {}. {} abandons the measure. Continue? [y/N]: ",
+            w.name, start.real_time.with_timezone(&Local).format("%Y-%m-%d %H:%M"), doing);
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)
+            .expect("Failed to read line");
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Leaving it be");
+            std::process::exit(1);
+        }
+    }
+    w.measures.pop();
+    println!("Abandoned the active measure of [{}]", w.name);
+}
+fn handle_position_test(name: String, reading: Reading, early: bool, restart: bool, report: bool, yes: bool, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    let settings = &config().position_test;
+    if settings.positions.is_empty() {
+        println!("No positions to test. Set position_test.positions in the config.");
+        std::process::exit(1);
+    }
+    let latest_test = w.measures.iter().filter_map(|m| m.leg.as_ref()).map(|l| l.test).max();
+    if report {
+        match latest_test {
+            Some(test) => print_position_report(&w, test),
+            None => println!("[{}] hasn't had a position test yet", w.name),
+        }
+        return;
+    }
+
+    // A position test moves the watch off the wrist, so a worn measure can't carry on through it
+    abandon_active_measure(&mut w, "Starting a position test", yes);
+    let active = w.measures.last().is_some_and(|m| m.leg.is_some() && m.measure_end.is_none());
+    if active && restart {
+        println!("Abandoning the leg in progress");
+        w.measures.pop();
+    }
+    let done = |w: &Watch, test: u32| -> Vec<Position> {
+        w.measures.iter()
+            .filter_map(|m| m.leg.as_ref().filter(|l| l.test == test && m.measure_end.is_some()))
+            .map(|l| l.position)
+            .collect()
+    };
+    // Carry on with the latest test unless it's finished, or we're starting over
+    let test = match latest_test {
+        Some(test) if !restart && settings.positions.iter().any(|p| !done(&w, test).contains(p)) => test,
+        Some(test) => test + 1,
+        None => 1,
+    };
+
+    if active && !restart {
+        let m = w.measures.last().unwrap();
+        let position = m.leg.as_ref().unwrap().position;
+        let start = m.measure_start.as_ref().unwrap().real_time;
+        let at = match reading.real_time {
+            Some(RealTime::Now(t) | RealTime::At(t)) => t,
+            None => Utc::now(),
+        };
+        let elapsed = at.signed_duration_since(start).num_seconds() as f64 / 3600.0;
+        if elapsed < settings.hours && !early {
+            let ready = start + chrono::TimeDelta::seconds((settings.hours * 3600.0) as i64);
+            println!("[{}] has been {} for {:.1} of {} hours. Come back at {}, or pass --early to move on now.",
+                w.name, position.to_str(), elapsed, settings.hours, ready.with_timezone(&Local).format("%Y-%m-%d %H:%M"));
+            std::process::exit(1);
+        }
+    }
+
+    println!("Position test {} for [{}]", test, w.name);
+    let reading = get_watch_time_pair(reading, &w);
+    if active && !restart {
+        let m = w.measures.last_mut().unwrap();
+        m.measure_end = Some(reading.clone());
+        w.update_running();
+        let m = w.measures.last().unwrap();
+        let position = m.leg.as_ref().unwrap().position.to_str();
+        match m.rate {
+            Some(rate) => println!("{}: {}", position, format_drift(rate, m.rate_sd, w.rate_unit())),
+            None => println!("{}: unmeasured, the readings were too close together", position),
+        }
+    }
+
+    // The reading that ends one leg starts the next, since the watch is just turned over
+    let remaining: Vec<Position> = settings.positions.iter()
+        .filter(|p| !done(&w, test).contains(p))
+        .copied()
+        .collect();
+    let Some(&next) = remaining.first() else {
+        w.save();
+        println!();
+        print_position_report(&w, test);
+        return;
+    };
+    let ready = reading.real_time + chrono::TimeDelta::seconds((settings.hours * 3600.0) as i64);
+    w.measures.push(Measure {
+        measure_start: Some(reading),
+        checks: Vec::new(),
+        measure_end: None,
+        rate: None,
+        rate_sd: None,
+        leg: Some(PositionLeg { test, position: next }),
+    });
+    w.save();
+    println!("\nLeave [{}] {} for {} hours, then run `wd position-test` again at {} ({} of {} positions left)",
+        w.name, next.to_str(), settings.hours, ready.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+        remaining.len(), settings.positions.len());
+}
+// Per-position rates of a position test, and which way to rest the watch overnight
+fn print_position_report(w: &Watch, test: u32) {
+    let unit = w.rate_unit();
+    let legs: Vec<(Position, &Measure)> = w.measures.iter()
+        .filter_map(|m| m.leg.as_ref().filter(|l| l.test == test && m.rate.is_some()).map(|l| (l.position, m)))
+        .collect();
+    if legs.is_empty() {
+        println!("No positions of test {} for [{}] have finished yet", test, w.name);
+        return;
+    }
+    println!("Position test {} for [{}]", test, w.name);
+    for (position, m) in &legs {
+        println!("  {:<12} {}", position.to_str(), format_drift(m.rate.unwrap(), m.rate_sd, unit));
+    }
+    let rates: Vec<f64> = legs.iter().map(|(_, m)| m.rate.unwrap()).collect();
+    let mean = rates.iter().sum::<f64>() / rates.len() as f64;
+    let (fastest, slowest) = (
+        legs.iter().max_by(|a, b| a.1.rate.unwrap().total_cmp(&b.1.rate.unwrap())).unwrap(),
+        legs.iter().min_by(|a, b| a.1.rate.unwrap().total_cmp(&b.1.rate.unwrap())).unwrap(),
+    );
+    println!("  Mean:  {:+.2} {}", unit.convert(mean), unit.to_str());
+    println!("  Delta: {:.2} {} ({} fastest, {} slowest)",
+        unit.convert(fastest.1.rate.unwrap() - slowest.1.rate.unwrap()), unit.to_str(),
+        fastest.0.to_str(), slowest.0.to_str());
+    if legs.len() < config().position_test.positions.len() {
+        println!("  ({} of {} positions done)", legs.len(), config().position_test.positions.len());
+    }
+
+    // Worn for the rest of the day, rested overnight: pick the position that brings the day's
+    // total closest to zero
+    let (worn, source) = match w.last_complete_measure() {
+        Some(m) => (m.rate.unwrap(), "its latest worn measure"),
+        None => (mean, "the mean of the positions, since it hasn't been measured worn"),
+    };
+    let night = config().position_test.night_hours / 24.0;
+    let daily = |rest: f64| worn * (1.0 - night) + rest * night;
+    let (best, best_rate) = legs.iter()
+        .map(|(p, m)| (*p, m.rate.unwrap()))
+        .min_by(|a, b| daily(a.1).abs().total_cmp(&daily(b.1).abs()))
+        .unwrap();
+    println!();
+    println!("Worn, it runs at {:+.2} {} (from {})", unit.convert(worn), unit.to_str(), source);
+    println!("Rest it {} overnight ({} hours) for about {:+.2} {} over the day",
+        best.to_str(), config().position_test.night_hours, unit.convert(daily(best_rate)), unit.to_str());
+}
+fn handle_trend(name: String, min: Option<f64>, max: Option<f64>, all: bool) {
+    let w = get_matching_watch(name, all);
+    let unit = w.rate_unit();
+    let measures: Vec<&Measure> = w.measures.iter()
+        .filter(|m| m.measure_start.is_some() && m.measure_end.is_some() && m.rate.is_some() && m.leg.is_none())
+        .collect();
+    if measures.len() < 2 {
+        println!("[{}] needs at least 2 completed measures to show a trend, it has {}", w.name, measures.len());
+        std::process::exit(1);
+    }
+
+    // Each measure's rate, placed at its midpoint, in days since the first midpoint
+    let midpoint = |m: &Measure| {
+        let start = m.measure_start.as_ref().unwrap().real_time;
+        let end = m.measure_end.as_ref().unwrap().real_time;
+        start + end.signed_duration_since(start) / 2
+    };
+    let first = midpoint(measures[0]);
+    let points: Vec<(f64, f64)> = measures.iter().map(|m| {
+        let days = midpoint(m).signed_duration_since(first).num_seconds() as f64 / 86400.0;
+        (days, unit.convert(m.rate.unwrap()))
+    }).collect();
+    let Some(fit) = linear_fit(&points) else {
+        println!("[{}]'s measures all overlap, so there's no trend to fit", w.name);
+        std::process::exit(1);
+    };
+
+    let now = Utc::now().signed_duration_since(first).num_seconds() as f64 / 86400.0;
+    let last = points[points.len() - 1].0;
+    let rate_last = fit.intercept + fit.slope * last;
+    let per_month = fit.slope * DAYS_PER_MONTH;
+    println!("Trend for [{}] over {} measures, {} to {}", w.name, measures.len(),
+        measures[0].measure_start.as_ref().unwrap().real_time.with_timezone(&Local).format("%Y-%m-%d"),
+        measures[measures.len() - 1].measure_end.as_ref().unwrap().real_time.with_timezone(&Local).format("%Y-%m-%d"));
+
+    // Only call it a trend if the slope stands out from the measures' scatter
+    let slope_sd = fit.residual_sd.map(|sd| sd / fit.sxx.sqrt());
+    let significant = slope_sd.is_some_and(|sd| fit.slope.abs() > 2.0 * sd);
+    // Without a real trend, the best guess at the current rate is the weighted mean of the measures
+    let all_measures = AggregateArgs { aggregate: true, last: None, since: None, until: None };
+    let rate_latest = if significant {
+        println!("  Rate at the last measure: {:+.2} {} by the trend line", rate_last, unit.to_str());
+        if now - last >= 1.0 {
+            println!("  Extrapolated to today: {:+.2} {} ({:.0} days past the last measure, if the trend holds)",
+                fit.intercept + fit.slope * now, unit.to_str(), now - last);
+        }
+        rate_last
+    } else {
+        let mean = unit.convert(w.summary(&all_measures).map_or(rate_last, |s| s.mean));
+        println!("  Rate: {:+.2} {} (weighted mean of the measures)", mean, unit.to_str());
+        mean
+    };
+    match slope_sd {
+        Some(sd) => println!("  Changing by {:+.2} ± {:.2} {} per month", per_month, sd * DAYS_PER_MONTH, unit.to_str()),
+        None => println!("  Changing by {:+.2} {} per month (2 measures, no error estimate)", per_month, unit.to_str()),
+    }
+    if !significant {
+        println!("  Steady: no significant change in rate");
+    } else if fit.slope > 0.0 {
+        println!("  Getting faster");
+    } else {
+        println!("  Getting slower");
+    }
+
+    // Compare the scatter around the trend in the older and newer half of the measures
+    if let Some(sd) = fit.residual_sd {
+        println!("  Scatter around the trend: {:.2} {}", sd, unit.to_str());
+        if fit.residuals.len() >= 4 {
+            let (older, newer) = fit.residuals.split_at(fit.residuals.len() / 2);
+            let rms = |r: &[f64]| (r.iter().map(|r| r.powi(2)).sum::<f64>() / r.len() as f64).sqrt();
+            let (older, newer) = (rms(older), rms(newer));
+            if newer > 1.5 * older {
+                println!("  More erratic lately: scatter {:.2} {} in recent measures vs {:.2} before", newer, unit.to_str(), older);
+            } else if older > 1.5 * newer {
+                println!("  Less erratic lately: scatter {:.2} {} in recent measures vs {:.2} before", newer, unit.to_str(), older);
+            }
+        }
+    }
+
+    let (default_min, default_max) = w.tolerance();
+    let (min, max) = (min.unwrap_or(default_min), max.unwrap_or(default_max));
+    println!("  Tolerance: {:+.2} to {:+.2} {}", min, max, unit.to_str());
+    if rate_latest < min || rate_latest > max {
+        println!("  Out of tolerance as of the last measure");
+        return;
+    }
+    let limit = match fit.slope {
+        _ if !significant => {
+            println!("  Not projected to leave tolerance, since the rate is steady");
+            return;
+        },
+        s if s > 0.0 => max,
+        s if s < 0.0 => min,
+        _ => {
+            println!("  Not projected to leave tolerance");
+            return;
+        },
+    };
+    let days_left = (limit - fit.intercept) / fit.slope - now;
+    let Some(exit) = projected_exit(Utc::now(), days_left) else {
+        println!("  Not projected to leave tolerance within range");
+        return;
+    };
+    if days_left < 0.0 {
+        // Crossed between the last measure and today, going by the trend line alone
+        println!("  Projected to have reached {:+.2} {} around {}, after the last measure. Measure again to check.",
+            limit, unit.to_str(), exit.with_timezone(&Local).format("%Y-%m-%d"));
+    } else {
+        println!("  Projected to reach {:+.2} {} around {} (in {:.1} months)", limit, unit.to_str(),
+            exit.with_timezone(&Local).format("%Y-%m-%d"), days_left / DAYS_PER_MONTH);
+    }
+}
+// When a trend `days_left` days from `now` is reached, or None if that's past what a date can hold.
+// A nearly flat trend puts it absurdly far out.
+fn projected_exit(now: DateTime<Utc>, days_left: f64) -> Option<DateTime<Utc>> {
+    let delta = chrono::TimeDelta::try_seconds((days_left * 86400.0) as i64)?;
+    now.checked_add_signed(delta)
+}
+fn handle_set_advice(name: String, until: DateTime<Utc>, target: AdviceTarget, agg: AggregateArgs, all: bool) {
+    let w = get_matching_watch(name, all);
+    let (rate, source) = if agg.aggregate {
+        match w.summary(&agg) {
+            Some(s) => (s.mean, format!("aggregate of {} measures", s.count)),
+            None => {
+                println!("[{}] has no completed measures in range", w.name);
+                std::process::exit(1);
+            },
+        }
+    } else {
+        match w.last_complete_measure() {
+            Some(m) => (m.rate.unwrap(), "latest measure".to_owned()),
+            None => {
+                println!("[{}] has no completed measures yet, so its rate is unknown", w.name);
+                std::process::exit(1);
+            },
+        }
+    };
+    // Leave some time to pull the crown and line the hands up
+    let now = Utc::now();
+    let set_at = (now + chrono::TimeDelta::seconds(90))
+        .with_second(0).unwrap()
+        .with_nanosecond(0).unwrap();
+    if until <= set_at {
+        println!("--until must be in the future");
+        std::process::exit(1);
+    }
+    let wear = until.signed_duration_since(set_at).num_seconds() as f64;
+    // Cancel out the drift accumulated by the target point, so the error swings from -x to +x
+    // around the midpoint, or ends at zero
+    let offset = match target {
+        AdviceTarget::Mid => -rate * wear / 2.0,
+        AdviceTarget::End => -rate * wear,
+    };
+    let tz = w.tz().or_else(get_system_tz);
+    let hands = set_at + chrono::TimeDelta::milliseconds((offset * 1000.0).round() as i64);
+    let hands_rounded = (hands + chrono::TimeDelta::milliseconds(500)).with_nanosecond(0).unwrap();
+
+    println!("[{}] runs at {:+.1} {} ({})", w.name, w.rate_unit().convert(rate), w.rate_unit().to_str(), source);
+    println!("Worn until {} ({:.1} days), closest to correct at the {}",
+        time_in_tz(until, tz).format("%Y-%m-%d %H:%M"), wear / 86400.0,
+        match target { AdviceTarget::Mid => "midpoint", AdviceTarget::End => "end" });
+    println!("Set it {}. It'll be {} at the start, {} halfway and {} at the end.",
+        format_offset(offset), format_offset(offset), format_offset(offset + rate * wear / 2.0), format_offset(offset + rate * wear));
+    println!();
+    println!("When the reference reads {}, set the hands to {}",
+        time_in_tz(set_at, tz).format("%H:%M:%S"), time_in_tz(hands_rounded, tz).format("%H:%M:%S"));
+}
+// e.g. "8.4s fast"
+fn format_offset(secs: f64) -> String {
+    match secs {
+        s if s.abs() < 0.05 => "spot on".to_owned(),
+        s if s > 0.0 => format!("{:.1}s fast", s),
+        s => format!("{:.1}s slow", -s),
+    }
+}
+// Seconds ahead of real time, e.g. +10s, -2.5s or 0
+fn parse_offset(s: &str) -> Result<f64, String> {
+    s.strip_suffix('s').unwrap_or(s).parse()
+        .map_err(|_| format!("expected seconds ahead like +10s or -2.5s, got [{}]", s))
+}
+// Presets for `--spec`: name, description, min, max, unit
+const SPEC_PRESETS: &[(&str, &str, f64, f64, RateUnit)] = &[
+    ("cosc", "COSC", -4.0, 6.0, RateUnit::Day),
+    ("metas", "METAS Master Chronometer", 0.0, 5.0, RateUnit::Day),
+    ("rolex", "Rolex Superlative Chronometer", -2.0, 2.0, RateUnit::Day),
+    ("gs", "Grand Seiko Standard", -3.0, 5.0, RateUnit::Day),
+    ("gs-special", "Grand Seiko Special Standard", -2.0, 4.0, RateUnit::Day),
+    ("spring-drive", "Spring Drive", -15.0, 15.0, RateUnit::Month),
+    ("gs-9f", "Grand Seiko 9F quartz", -10.0, 10.0, RateUnit::Year),
+    ("eta-2824", "ETA 2824-2 standard", -12.0, 12.0, RateUnit::Day),
+    ("sw200", "Sellita SW200-1 standard", -12.0, 12.0, RateUnit::Day),
+    ("nh35", "Seiko NH35", -20.0, 40.0, RateUnit::Day),
+    ("6r", "Seiko 6R", -15.0, 25.0, RateUnit::Day),
+    ("miyota-9", "Miyota 9000 series", -10.0, 30.0, RateUnit::Day),
+    ("quartz", "Typical quartz", -15.0, 15.0, RateUnit::Month),
+];
+// A preset name, or a range like -4/+6 s/day or ±15 s/month
+fn parse_spec(s: &str) -> Result<Spec, String> {
+    if let Some((_, name, min, max, unit)) = SPEC_PRESETS.iter().find(|p| p.0.eq_ignore_ascii_case(s.trim())) {
+        return Ok(Spec { min: *min, max: *max, unit: *unit, name: Some((*name).to_owned()) });
+    }
+    let presets: Vec<&str> = SPEC_PRESETS.iter().map(|p| p.0).collect();
+    let invalid = || format!("expected a preset ({}) or a range like -4/+6 s/day or ±15 s/month, got [{}]",
+        presets.join(", "), s);
+
+    let re = regex::Regex::new(r"^\s*(?:(?:±|\+-|\+/-)\s*(\d+(?:\.\d+)?)|([+-]?\d+(?:\.\d+)?)\s*/\s*([+-]?\d+(?:\.\d+)?))\s*(\S+)\s*$").unwrap();
+    let caps = re.captures(s).ok_or_else(invalid)?;
+    let (min, max) = match caps.get(1) {
+        Some(plus_minus) => {
+            let x: f64 = plus_minus.as_str().parse().unwrap();
+            (-x, x)
+        },
+        None => (caps[2].parse().unwrap(), caps[3].parse().unwrap()),
+    };
+    let unit = <RateUnit as clap::ValueEnum>::value_variants().iter()
+        .copied()
+        .find(|u| u.to_str() == &caps[4])
+        .ok_or_else(invalid)?;
+    if min > max {
+        return Err(format!("the spec's minimum is above its maximum in [{}]", s));
+    }
+    Ok(Spec { min, max, unit, name: None })
+}
+// A point in time, or a duration from now like 8h, 3d, 1w, or 1d12h
+fn parse_until(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_until_from(s, Utc::now())
+}
+fn parse_until_from(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let duration_re = regex::Regex::new(r"^(?:(\d+(?:\.\d+)?)([mhdw]))+$").unwrap();
+    if duration_re.is_match(s) {
+        let part_re = regex::Regex::new(r"(\d+(?:\.\d+)?)([mhdw])").unwrap();
+        let mut secs = 0.0;
+        for part in part_re.captures_iter(s) {
+            let n: f64 = part[1].parse().unwrap();
+            secs += n * match &part[2] {
+                "m" => 60.0,
+                "h" => 3600.0,
+                "d" => 86400.0,
+                _ => 604800.0,
+            };
+        }
+        return chrono::TimeDelta::try_seconds(secs as i64)
+            .and_then(|d| now.checked_add_signed(d))
+            .ok_or_else(|| format!("duration too large: [{}]", s));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let local = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_time(NaiveTime::MIN)))
+        .map_err(|_| format!("expected a duration like 8h, 3d or 1w, or a time like \"2024-05-03 18:00\", got [{}]", s))?;
+    Local.from_local_datetime(&local)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or_else(|| format!("[{}] doesn't exist in the local timezone", s))
+}
+fn handle_ls(query: String, ids: bool, agg: AggregateArgs, units: Option<RateUnit>, all: bool) {
+    let watches = get_matching_watches(&query, all);
     for w in watches {
+        let unit = units.unwrap_or(w.rate_unit());
         // Name
-        println!("Name: {}", w.name);
+        if ids {
+            println!("Name: {} (@{})", w.name, w.id);
+        } else {
+            println!("Name: {}", w.name);
+        }
 
         // Movement
         println!("  Movement: {}", w.movement.to_str());
+        if let Some(tz) = &w.watch_tz {
+            println!("  Kept on: {}", tz);
+        }
+        if w.archived {
+            println!("  Archived");
+        }
 
         // Measure/Drift
         if let Some(m) = w.last_complete_measure() {
-            println!("  Running at: {:+} seconds per {}", m.drift.unwrap(), w.movement.unit_str());
+            println!("  Running at: {}", format_drift(m.rate.unwrap(), m.rate_sd, unit));
             let (val, units) = m.measure_duration_and_units();
             println!("  Measured over: {} {}", val, units);
         } else {
             println!("  No completed measures yet");
         }
+        if let Some(spec) = &w.spec {
+            println!("  Spec: {}, {}", spec, w.spec_status(&agg).to_str());
+        }
+        if agg.aggregate {
+            match w.summary(&agg) {
+                Some(s) => {
+                    let s = s.in_unit(unit);
+                    println!("  Aggregate: {:+.2} {} over {} measures ({:.2} days)", s.mean, unit.to_str(), s.count, s.days);
+                    println!("    Median {:+.2}, std dev {:.2}, range {:+.2} to {:+.2} {}",
+                        s.median, s.std_dev, s.min, s.max, unit.to_str());
+                },
+                None => println!("  Aggregate: no completed measures in range"),
+            }
+        }
+
+        // Current error, projected from the latest reading or setting
+        if let Some((from, offset, sigma)) = w.estimated_offset(Utc::now()) {
+            let (val, units) = get_measure_duration_and_units(from, Utc::now());
+            match sigma {
+                Some(sigma) => println!("  Estimated now: {} ± {:.1}s (from {} {} ago)", format_offset(offset), sigma, val, units),
+                None => println!("  Last known: {}, {} {} ago", format_offset(offset), val, units),
+            }
+        }
 
         // Active Measures
         if let Some(m) = w.measures.last() {
-            if let None = m.measure_end {
+            if m.measure_end.is_none() {
                 let start = m.measure_start.clone().unwrap();
-                let (val, units) = get_measure_duration_and_units(start.real_time, Local::now());
+                let (val, units) = get_measure_duration_and_units(start.real_time, Utc::now());
                 println!("  Active measure, started {} {} ago", val, units);
             }
         }
@@ -140,18 +757,18 @@ fn handle_ls(query: String) {
         // Logs
         println!("  Worn on {} days", w.logs.len());
 
-        println!("");
+        println!();
     }
 }
-fn handle_recalculate(query: String) {
-    let mut watches= get_matching_watches(&query);
+fn handle_recalculate(query: String, all: bool) {
+    let mut watches = get_matching_watches(&query, all);
     for w in &mut watches {
         w.update_running();
-        w.save();
     }
+    save_watches(&watches);
 }
-fn handle_log(name: String) {
-    let mut w = get_matching_watch(name);
+fn handle_log(name: String, all: bool) {
+    let mut w = get_matching_watch(name, all);
     println!("Tracking log for [{}]", w.name);
     print!("Confirm? [Enter], ^C to cancel: ");
     io::stdout().flush().unwrap();
@@ -168,21 +785,268 @@ fn handle_log(name: String) {
         println!("Already logged watch for today, not adding again (worn on {} days)", w.logs.len());
     }
 }
-fn handle_print() {
-    let watches = get_matching_watches("");
-    print_markdown_table(watches);
+fn handle_print(agg: AggregateArgs, units: Option<RateUnit>, all: bool) {
+    let watches = get_matching_watches("", all);
+    print_markdown_table(watches, &agg, units);
+}
+fn handle_rename(name: String, to: Option<String>, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    let to = if let Some(to) = to {
+        to
+    } else {
+        print!("New name for [{}]: ", w.name);
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)
+            .expect("Failed to read line");
+        input.trim().to_owned()
+    };
+    if to.is_empty() {
+        println!("Name can't be empty");
+        std::process::exit(1);
+    }
+
+    println!("Renaming [{}] to [{}]", w.name, to);
+    w.name = to;
+    w.save();
+}
+fn handle_edit(name: String, movement: Option<Movement>, unit: Option<RateUnit>, spec: Option<Option<Spec>>,
+    watch_tz: Option<Option<Tz>>, all: bool) {
+    let mut w = get_matching_watch(name, all);
+    if movement.is_none() && unit.is_none() && spec.is_none() && watch_tz.is_none() {
+        println!("Nothing to change. Pass -m <movement>, -u <unit>, --spec <spec> or -z <zone>.");
+        std::process::exit(1);
+    }
+    if let Some(movement) = movement {
+        println!("Changing [{}] from {} to {}", w.name, w.movement.to_str(), movement.to_str());
+        w.movement = movement;
+    }
+    if let Some(unit) = unit {
+        println!("Reporting [{}] in {} instead of {}", w.name, unit.to_str(), w.rate_unit().to_str());
+        w.unit = Some(unit);
+    }
+    if let Some(spec) = spec {
+        match &spec {
+            Some(spec) => println!("Setting [{}]'s spec to {}", w.name, spec),
+            None => println!("Clearing [{}]'s spec", w.name),
+        }
+        w.spec = spec;
+    }
+    if let Some(watch_tz) = watch_tz {
+        match watch_tz {
+            Some(tz) => println!("Reading [{}] in {} from now on", w.name, tz.name()),
+            None => println!("Reading [{}] in the system timezone from now on", w.name),
+        }
+        w.watch_tz = watch_tz.map(|tz| tz.name().to_owned());
+    }
+    w.save();
+}
+fn handle_rm(name: String, yes: bool, all: bool) {
+    let w = get_matching_watch(name, all);
+    if !yes {
+        print!("Delete [{}] along with {} measures and {} logs? This can't be undone. [y/N]: ",
+            w.name, w.measures.len(), w.logs.len());
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)
+            .expect("Failed to read line");
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Not deleting");
+            return;
+        }
+    }
+
+    w.delete();
+    println!("Deleted [{}]", w.name);
+}
+fn handle_archive(name: String, archive: bool) {
+    // Only archived watches can be unarchived, so always search them
+    let mut w = get_matching_watch(name, true);
+    if w.archived == archive {
+        println!("[{}] is already {}", w.name, if archive { "archived" } else { "active" });
+        return;
+    }
+
+    w.archived = archive;
+    w.save();
+    if archive {
+        println!("Archived [{}]. Its history is kept; `wd unarchive` brings it back.", w.name);
+    } else {
+        println!("Unarchived [{}]", w.name);
+    }
+}
+fn handle_migrate(dry_run: bool) {
+    let path = get_path();
+    let Some(contents) = read_file() else {
+        println!("No database at [{:?}], nothing to migrate", path);
+        return;
+    };
+    let (db, applied) = parse_database(&contents);
+    if applied.is_empty() {
+        println!("Database [{:?}] is already at schema version {}", path, SCHEMA_VERSION);
+        return;
+    }
+
+    println!("Migrations for [{:?}]:", path);
+    for step in &applied {
+        println!("  {}", step);
+    }
+    if dry_run {
+        println!("Dry run: {} watches would be rewritten at schema version {}", db.watches.len(), SCHEMA_VERSION);
+        return;
+    }
+    save_file(db.watches);
+    if config().backups > 0 {
+        println!("Migrated to schema version {}. Previous version kept at [{:?}]", SCHEMA_VERSION, get_backup_path(1));
+    } else {
+        println!("Migrated to schema version {}", SCHEMA_VERSION);
+    }
+}
+fn handle_config(action: ConfigAction) {
+    match action {
+        ConfigAction::Show => {
+            println!("# {}", get_config_path().display());
+            print!("{}", toml::to_string_pretty(config()).unwrap());
+        },
+        ConfigAction::Get { key } => {
+            let effective = toml::Value::try_from(config()).unwrap();
+            match get_config_value(&effective, &key) {
+                Some(toml::Value::String(s)) => println!("{}", s),
+                Some(v) => println!("{}", v),
+                None => {
+                    println!("Unknown or unset config key [{}]", key);
+                    std::process::exit(1);
+                },
+            }
+        },
+        ConfigAction::Set { key, value } => {
+            // `db` is optional, so it won't show up in the effective config until it's set
+            let effective = toml::Value::try_from(config()).unwrap();
+            if key != "db" && get_config_value(&effective, &key).is_none() {
+                println!("Unknown config key [{}]", key);
+                std::process::exit(1);
+            }
+
+            // Edit the file as written rather than the effective config, so unset keys keep
+            // following the built-in defaults
+            let path = get_config_path();
+            let mut table = try_read_config_table(&path).unwrap_or_else(|e| {
+                // Nothing can be salvaged from a file that isn't TOML, so start over next to it
+                let broken = path_with_suffix(&path, ".broken");
+                println!("{}", e);
+                fs::rename(&path, &broken)
+                    .unwrap_or_else(|e| panic!("Can't move [{:?}] aside: {}", path, e));
+                println!("Moved it to [{:?}] and started a new config", broken);
+                toml::Table::new()
+            });
+            let value = parse_config_value(&value);
+            // Only check the key being set, so one bad value doesn't stop the others being fixed
+            let mut alone = toml::Table::new();
+            set_config_value(&mut alone, &key, value.clone());
+            if let Err(e) = alone.try_into::<Config>() {
+                println!("Invalid value for [{}]: {}", key, e.message());
+                std::process::exit(1);
+            }
+            set_config_value(&mut table, &key, value.clone());
+
+            write_config_table(&path, &table);
+            println!("Set {} = {}", key, value);
+        },
+    }
+}
+fn handle_calibrate(trials: usize) {
+    if !io::stdin().is_terminal() {
+        println!("stdin is not a terminal, so there's nothing to calibrate");
+        std::process::exit(1);
+    }
+    // Seconds between ticks, enough to see each one coming
+    const PERIOD_MS: i64 = 5000;
+    println!("Press [Enter] each time the countdown reaches ●, the way you would at the watch's :00.");
+    println!("{} trials, [Esc] to cancel.", trials);
+    let mut stdout = io::stdout();
+    let (cursor_x, cursor_y) = crossterm::cursor::position().unwrap();
+
+    let mut latencies: Vec<f64> = Vec::new();
+    let mut last_drawn = String::new();
+    crossterm::terminal::enable_raw_mode().unwrap();
+    while latencies.len() < trials {
+        let phase = Utc::now().timestamp_millis().rem_euclid(PERIOD_MS);
+        let tick = match phase {
+            p if p < 1000 => "●".to_owned(),
+            p => ((PERIOD_MS - p) / 1000 + 1).to_string(),
+        };
+        let status = match latencies.last() {
+            Some(l) => format!("[{}]  trial {}/{}, last press {:+.3}s", tick, latencies.len() + 1, trials, l),
+            None => format!("[{}]  trial 1/{}", tick, trials),
+        };
+        if status != last_drawn {
+            crossterm::queue!(stdout,
+                crossterm::cursor::MoveTo(cursor_x, cursor_y),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+                crossterm::style::Print(&status),
+            ).unwrap();
+            stdout.flush().unwrap();
+            last_drawn = status;
+        }
+
+        if crossterm::event::poll(std::time::Duration::from_millis(5)).unwrap() {
+            if let crossterm::event::Event::Key(key_event) = crossterm::event::read().unwrap() {
+                match key_event.code {
+                    KeyCode::Enter => {
+                        // Early presses count too, so go by the nearest tick
+                        let ms = Utc::now().timestamp_millis();
+                        let nearest = (ms + PERIOD_MS / 2).div_euclid(PERIOD_MS) * PERIOD_MS;
+                        latencies.push((ms - nearest) as f64 / 1000.0);
+                    },
+                    KeyCode::Esc => {
+                        crossterm::terminal::disable_raw_mode().unwrap();
+                        println!("\n\nCalibration cancelled, config unchanged");
+                        return;
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+    crossterm::terminal::disable_raw_mode().unwrap();
+    println!("\n");
+
+    let n = latencies.len() as f64;
+    let mean = latencies.iter().sum::<f64>() / n;
+    let sd = (latencies.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    println!("Your presses land {:.3}s {} on average, give or take {:.3}s",
+        mean.abs(), if mean >= 0.0 { "late" } else { "early" }, sd);
+
+    let path = get_config_path();
+    let mut table = read_config_table(&path);
+    let round = |x: f64| (x * 1000.0).round() / 1000.0;
+    table.insert("reaction_latency_secs".to_owned(), toml::Value::Float(round(mean)));
+    table.insert("reaction_jitter_secs".to_owned(), toml::Value::Float(round(sd)));
+    write_config_table(&path, &table);
+    println!("Saved reaction_latency_secs = {} and reaction_jitter_secs = {} to {}",
+        round(mean), round(sd), path.display());
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
-fn get_matching_watch(query: String) -> Watch {
-    let matches = get_matching_watches(&query);
+fn get_matching_watch(query: String, all: bool) -> Watch {
+    let matches = get_matching_watches(&query, all);
     if matches.is_empty() {
-        println!("No matches for regex [{}]", query);
+        match query.strip_prefix('@') {
+            Some(id) => println!("No watch with ID [{}]", id),
+            None => println!("No matches for regex [{}]", query),
+        }
         std::process::exit(1);
     }
     if matches.len() > 1 {
-        println!("Multiple matches for regex [{}]:\n", query);
+        if !io::stdin().is_terminal() {
+            println!("Multiple matches for [{}]. Narrow the search or select by @<id>:", query);
+            for w in &matches {
+                println!("  @{} {}", w.id, w.name);
+            }
+            std::process::exit(1);
+        }
+        println!("Multiple matches for [{}]:\n", query);
         return get_one_watch_from_matches(matches);
     }
     matches[0].clone()
@@ -191,8 +1055,16 @@ fn get_one_watch_from_matches(mut watches: Vec<Watch>) -> Watch {
     println!("Choose with arrow keys:");
     watches.sort_by_key(|w| w.logs.len());
     watches.reverse();
-    for (idx, watch) in watches.iter().enumerate() {
-        println!("[{}] {}", idx, watch.name);
+    // Show IDs for names that are ambiguous on their own
+    let labels: Vec<String> = watches.iter().map(|w| {
+        if watches.iter().filter(|other| other.name == w.name).count() > 1 {
+            format!("{} (@{})", w.name, w.id)
+        } else {
+            w.name.clone()
+        }
+    }).collect();
+    for (idx, label) in labels.iter().enumerate() {
+        println!("[{}] {}", idx, label);
     }
 
     let mut stdout = io::stdout();
@@ -211,10 +1083,10 @@ fn get_one_watch_from_matches(mut watches: Vec<Watch>) -> Watch {
         crossterm::execute!(stdout,
             crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
             crossterm::cursor::MoveTo(0, pre_move_y),
-            crossterm::style::Print(format!("[{}] {}", pre_idx, watches[pre_idx].name)),
+            crossterm::style::Print(format!("[{}] {}", pre_idx, labels[pre_idx])),
             crossterm::cursor::MoveTo(0, cursor_idx as u16 + y_offset),
             crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
-            crossterm::style::Print(format!("[{}] --> {} <--", cursor_idx, watches[cursor_idx].name)),
+            crossterm::style::Print(format!("[{}] --> {} <--", cursor_idx, labels[cursor_idx])),
         ).unwrap();
         stdout.flush().unwrap();
     };
@@ -225,24 +1097,18 @@ fn get_one_watch_from_matches(mut watches: Vec<Watch>) -> Watch {
         if crossterm::event::poll(std::time::Duration::from_millis(150)).unwrap() {
             if let crossterm::event::Event::Key(key_event) = crossterm::event::read().unwrap() {
                 match key_event.code {
-                    KeyCode::Up => {
-                        if cursor_idx > 0 {
-                            cursor_idx -= 1;
-                            update_selection(cursor_idx);
-                        }
+                    KeyCode::Up if cursor_idx > 0 => {
+                        cursor_idx -= 1;
+                        update_selection(cursor_idx);
                     },
-                    KeyCode::Down => {
-                        if cursor_idx < watches.len()-1 {
-                            cursor_idx += 1;
-                            update_selection(cursor_idx);
-                        }
+                    KeyCode::Down if cursor_idx < watches.len()-1 => {
+                        cursor_idx += 1;
+                        update_selection(cursor_idx);
                     },
-                    KeyCode::Char('c') => {
-                        if key_event.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) {
-                            crossterm::terminal::disable_raw_mode().unwrap();
-                            print!("{}", "\n".repeat(1 + watches.len() - cursor_idx));
-                            panic!("Got CTRL-C, hard quitting");
-                        }
+                    KeyCode::Char('c') if key_event.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                        crossterm::terminal::disable_raw_mode().unwrap();
+                        print!("{}", "\n".repeat(1 + watches.len() - cursor_idx));
+                        panic!("Got CTRL-C, hard quitting");
                     },
                     KeyCode::Enter => break,
                     _ => {},
@@ -256,48 +1122,291 @@ fn get_one_watch_from_matches(mut watches: Vec<Watch>) -> Watch {
     watches[cursor_idx].clone()
 }
 
-fn get_00_time() -> DateTime<Local> {
-    print!("Press [Enter] at watch's :00... ");
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)
-        .expect("Failed to read line");
+// Takes a reading for a measure, prompting for whatever wasn't passed on the command line
+fn get_watch_time_pair(reading: Reading, watch: &Watch) -> WatchTimePair {
+    let system_tz = get_system_tz();
+    let watch_tz = reading.watch_tz.or_else(|| watch.tz()).or(system_tz);
+    let real_time = match reading.real_time {
+        Some(RealTime::At(t)) => t,
+        Some(RealTime::Now(t)) => t,
+        None => {
+            require_terminal("--real-time (or --at now)");
+            get_00_time(watch_tz)
+        },
+    };
+    // Only a reading taken off this machine's clock can be corrected for its error
+    let (clock_offset, clock_delay) = match reading.real_time {
+        Some(RealTime::At(_)) => (None, None),
+        _ => check_clock(),
+    };
+    let (time, precision) = match reading.watch_time {
+        Some(reading) => reading,
+        None => {
+            require_terminal("--watch-time");
+            get_watch_time_from_real_time(time_in_tz(real_time, watch_tz).time())
+        },
+    };
+
+    let watch_time = match watch_tz {
+        Some(tz) => resolve_watch_time(&real_time.with_timezone(&tz), time).fixed_offset(),
+        // The system timezone has no IANA name we know of, so go by the offsets it reports
+        None => resolve_watch_time(&real_time.with_timezone(&Local), time).fixed_offset(),
+    };
+    WatchTimePair {
+        real_time,
+        watch_time: watch_time.with_timezone(&Utc),
+        watch_offset: watch_time.offset().local_minus_utc(),
+        system_tz: system_tz.map(|tz| tz.name().to_owned()),
+        precision,
+        clock_offset,
+        clock_delay,
+    }
+}
+// How far off the system clock is, per the configured SNTP server, as (offset, round trip delay)
+// in seconds. Both are None if the check is turned off or the server can't be reached.
+fn check_clock() -> (Option<f64>, Option<f64>) {
+    let sntp = &config().sntp;
+    if sntp.server.is_empty() {
+        return (None, None);
+    }
+    match sntp_query(&sntp.server, std::time::Duration::from_millis(sntp.timeout_ms)) {
+        Ok((offset, delay)) => {
+            println!("System clock is {:.3}s {} {} (round trip {:.3}s)",
+                offset.abs(), if offset >= 0.0 { "behind" } else { "ahead of" }, sntp.server, delay);
+            (Some(offset), Some(delay))
+        },
+        Err(e) => {
+            println!("Couldn't check the system clock against {} ({}), so it's taken as correct", sntp.server, e);
+            (None, None)
+        },
+    }
+}
+// Asks an SNTP server (RFC 4330) for the time. Returns the offset of the server's clock from the
+// system clock, and the round trip delay, in seconds.
+fn sntp_query(server: &str, timeout: std::time::Duration) -> io::Result<(f64, f64)> {
+    // Port 123 unless one's given
+    let addr = server.to_socket_addrs()
+        .or_else(|_| (server, 123).to_socket_addrs())?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(addr)?;
+
+    // Version 4, client mode, with our transmit time for the server to echo back
+    let mut request = [0u8; 48];
+    request[0] = 0b00_100_011;
+    let t1 = Utc::now();
+    request[40..48].copy_from_slice(&to_ntp_timestamp(t1).to_be_bytes());
+    socket.send(&request)?;
+
+    let mut response = [0u8; 48];
+    loop {
+        let len = socket.recv(&mut response)?;
+        let t4 = Utc::now();
+        // Ignore anything that isn't a reply to this request
+        if len < 48 || response[24..32] != request[40..48] {
+            continue;
+        }
+        // Sanity checks from RFC 4330 section 5
+        if response[0] & 0b111 != 4 {
+            return Err(io::Error::other("reply isn't in server mode"));
+        }
+        if response[0] >> 6 == 3 {
+            return Err(io::Error::other("server clock isn't synchronized"));
+        }
+        if response[1] == 0 {
+            return Err(io::Error::other("server sent a kiss-o'-death"));
+        }
+        if response[40..48] == [0; 8] {
+            return Err(io::Error::other("reply has no transmit timestamp"));
+        }
+        let t2 = from_ntp_timestamp(u64::from_be_bytes(response[32..40].try_into().unwrap()));
+        let t3 = from_ntp_timestamp(u64::from_be_bytes(response[40..48].try_into().unwrap()));
+        let (t1, t4) = (unix_secs(t1), unix_secs(t4));
+        return Ok((((t2 - t1) + (t3 - t4)) / 2.0, (t4 - t1) - (t3 - t2)));
+    }
+}
+// NTP timestamps count from 1900, in 32.32 fixed point seconds
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+fn to_ntp_timestamp(t: DateTime<Utc>) -> u64 {
+    let secs = t.timestamp() as u64 + NTP_UNIX_OFFSET;
+    let frac = ((t.timestamp_subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+fn from_ntp_timestamp(ts: u64) -> f64 {
+    (ts >> 32) as f64 - NTP_UNIX_OFFSET as f64 + (ts & 0xffff_ffff) as f64 / 4_294_967_296.0
+}
+fn unix_secs(t: DateTime<Utc>) -> f64 {
+    t.timestamp() as f64 + t.timestamp_subsec_nanos() as f64 / 1e9
+}
+// `t` as a wall clock time in `tz`, or in the system timezone if None
+fn time_in_tz(t: DateTime<Utc>, tz: Option<Tz>) -> NaiveDateTime {
+    match tz {
+        Some(tz) => t.with_timezone(&tz).naive_local(),
+        None => t.with_timezone(&Local).naive_local(),
+    }
+}
+fn get_system_tz() -> Option<Tz> {
+    iana_time_zone::get_timezone().ok()?.parse().ok()
+}
+fn parse_tz(s: &str) -> Result<Tz, String> {
+    s.parse().map_err(|_| format!("expected an IANA timezone like Europe/London, got [{}]", s))
+}
+fn require_terminal(flag: &str) {
+    if !io::stdin().is_terminal() {
+        println!("stdin is not a terminal, so wd can't prompt for the reading. Pass {} instead.", flag);
+        std::process::exit(1);
+    }
+}
+fn parse_watch_time(s: &str) -> Result<(NaiveTime, TimePrecision), String> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .map(|t| (t, TimePrecision::Second))
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M").map(|t| (t, TimePrecision::Minute)))
+        .map_err(|_| format!("expected HH:MM or HH:MM:SS, got [{}]", s))
+}
+fn parse_real_time(s: &str) -> Result<RealTime, String> {
+    if s.eq_ignore_ascii_case("now") {
+        return Ok(RealTime::Now(Utc::now()));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| RealTime::At(t.with_timezone(&Utc)))
+        .map_err(|e| format!("expected `now` or an RFC 3339 time like 2024-05-01T14:32:00-07:00 ({})", e))
+}
 
-    Local::now()
+fn get_00_time(tz: Option<Tz>) -> DateTime<Utc> {
+    wait_for_enter("Press [Enter] at watch's :00 ", tz)
 }
+// Shows the reference time ticking in `tz` until [Enter] is pressed, and returns when it was meant
+// to be pressed, taking out the usual delay measured by `wd calibrate`
+fn wait_for_enter(prompt: &str, tz: Option<Tz>) -> DateTime<Utc> {
+    let mut stdout = io::stdout();
+    print!("{}", prompt);
+    stdout.flush().unwrap();
+    let (cursor_x, cursor_y) = crossterm::cursor::position().unwrap();
+    let countdown = config().countdown;
 
-fn get_watch_time_from_real_time(t: DateTime<Local>) -> DateTime<Local> {
+    let mut last_drawn = String::new();
+    let mut last_beep = None;
+    crossterm::terminal::enable_raw_mode().unwrap();
+    let pressed = loop {
+        let now = time_in_tz(Utc::now(), tz);
+        // Leap seconds show up as a nanosecond count past one second
+        let tenths = (now.nanosecond() / 100_000_000).min(9);
+        let clock = format!("[{}.{}]", now.format("%H:%M:%S"), tenths);
+        // Count down the last five seconds of each minute
+        let secs_left = 60 - now.second();
+        let counting = secs_left <= 5;
+        if clock != last_drawn {
+            crossterm::queue!(stdout,
+                crossterm::cursor::MoveTo(cursor_x, cursor_y),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+                crossterm::style::Print(&clock),
+            ).unwrap();
+            if counting && matches!(countdown, Countdown::Visual | Countdown::Both) {
+                crossterm::queue!(stdout,
+                    crossterm::style::Print(" "),
+                    crossterm::style::PrintStyledContent(format!(" {} ", secs_left).reverse()),
+                ).unwrap();
+            }
+            // A short beep for each second of the countdown, and one at :00
+            if (counting || now.second() == 0) && last_beep != Some(now.second())
+                && matches!(countdown, Countdown::Audible | Countdown::Both) {
+                crossterm::queue!(stdout, crossterm::style::Print("\x07")).unwrap();
+                last_beep = Some(now.second());
+            }
+            stdout.flush().unwrap();
+            last_drawn = clock;
+        }
+
+        if crossterm::event::poll(std::time::Duration::from_millis(20)).unwrap() {
+            if let crossterm::event::Event::Key(key_event) = crossterm::event::read().unwrap() {
+                match key_event.code {
+                    KeyCode::Enter => break Utc::now(),
+                    // Raw mode swallows Ctrl-C, so handle it here
+                    KeyCode::Char('c') if key_event.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                        crossterm::terminal::disable_raw_mode().unwrap();
+                        println!();
+                        std::process::exit(130);
+                    },
+                    _ => {},
+                }
+            }
+        }
+    };
+    crossterm::terminal::disable_raw_mode().unwrap();
+    println!();
+
+    pressed - chrono::TimeDelta::milliseconds((config().reaction_latency_secs * 1000.0).round() as i64)
+}
+
+// `t` is the real time as it would show on the watch, i.e. in the timezone the watch is set to.
+// Returns the time the user says the watch shows.
+fn get_watch_time_from_real_time(t: NaiveTime) -> (NaiveTime, TimePrecision) {
     let mut stdout = io::stdout();
-    let mut watch_time = t.checked_add_signed(chrono::TimeDelta::seconds(55)).unwrap();
-    print!("Enter watch time, adjust with ↑/↓: [{}]", watch_time.format("%H:%M"));
-    // print!("Enter watch time, adjust with [Up]/[Down]: [{}]", watch_time.format("%H:%M"));
+    // Pre-fill with the minute the watch is about to tick over to
+    let lead = chrono::TimeDelta::seconds(config().watch_time_lead_secs);
+    let mut watch_time = t.overflowing_add_signed(lead).0.with_second(0).unwrap().with_nanosecond(0).unwrap();
+    // Selected field: 0 = hours, 1 = minutes, 2 = seconds
+    let mut field = 1;
+    let mut precision = TimePrecision::Minute;
+    print!("Enter watch time, pick a field with ←/→, adjust with ↑/↓: ");
     stdout.flush().unwrap();
 
-    let (_cursor_x, cursor_y) = crossterm::cursor::position().unwrap();
+    let (cursor_x, cursor_y) = crossterm::cursor::position().unwrap();
 
-    // closure: Redraw time
-    let mut update_time = |time: &DateTime<Local>| {
-        // 35/38/42?
-        crossterm::execute!(stdout,
-            crossterm::cursor::MoveTo(35, cursor_y),
+    // closure: Redraw time, highlighting the selected field
+    let mut update_time = |time: NaiveTime, field: usize| {
+        let parts = [time.format("%H").to_string(), time.format("%M").to_string(), time.format("%S").to_string()];
+        crossterm::queue!(stdout,
+            crossterm::cursor::MoveTo(cursor_x, cursor_y),
             crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
-            crossterm::style::Print(format!("[{}]", time.format("%H:%M"))),
+            crossterm::style::Print("["),
         ).unwrap();
+        for (idx, part) in parts.into_iter().enumerate() {
+            if idx > 0 {
+                crossterm::queue!(stdout, crossterm::style::Print(":")).unwrap();
+            }
+            if idx == field {
+                crossterm::queue!(stdout, crossterm::style::PrintStyledContent(part.reverse())).unwrap();
+            } else {
+                crossterm::queue!(stdout, crossterm::style::Print(part)).unwrap();
+            }
+        }
+        crossterm::queue!(stdout, crossterm::style::Print("]")).unwrap();
         stdout.flush().unwrap();
     };
+    let step = |field: usize| match field {
+        0 => chrono::TimeDelta::hours(1),
+        1 => chrono::TimeDelta::minutes(1),
+        _ => chrono::TimeDelta::seconds(1),
+    };
 
+    update_time(watch_time, field);
     crossterm::terminal::enable_raw_mode().unwrap();
     loop {
         if crossterm::event::poll(std::time::Duration::from_millis(150)).unwrap() {
             if let crossterm::event::Event::Key(key_event) = crossterm::event::read().unwrap() {
                 match key_event.code {
                     KeyCode::Up => {
-                        watch_time = watch_time.checked_add_signed(chrono::TimeDelta::minutes(1)).unwrap();
-                        update_time(&watch_time);
+                        watch_time = watch_time.overflowing_add_signed(step(field)).0;
+                        update_time(watch_time, field);
                     },
                     KeyCode::Down => {
-                        watch_time = watch_time.checked_sub_signed(chrono::TimeDelta::minutes(1)).unwrap();
-                        update_time(&watch_time);
+                        watch_time = watch_time.overflowing_sub_signed(step(field)).0;
+                        update_time(watch_time, field);
+                    },
+                    KeyCode::Left if field > 0 => {
+                        field -= 1;
+                        update_time(watch_time, field);
+                    },
+                    KeyCode::Right if field < 2 => {
+                        field += 1;
+                        // Once the seconds are looked at, they're part of the reading
+                        if field == 2 {
+                            precision = TimePrecision::Second;
+                        }
+                        update_time(watch_time, field);
                     },
                     KeyCode::Enter => break,
                     _ => {},
@@ -309,16 +1418,52 @@ fn get_watch_time_from_real_time(t: DateTime<Local>) -> DateTime<Local> {
 
     println!("\n");
 
-    Local.with_ymd_and_hms(t.year(), t.month(), t.day(),
-            watch_time.hour(), watch_time.minute(), 00).unwrap()
+    (watch_time, precision)
+}
+// The instant nearest to `real_time` at which a clock in its timezone shows `time`. A reading has
+// no date, so it's taken to be within 12 hours either side of the real time, e.g. a watch showing
+// 00:00:05 at 23:59:50 is read as a few seconds into the next day.
+fn resolve_watch_time<Tz: TimeZone>(real_time: &DateTime<Tz>, time: NaiveTime) -> DateTime<Tz> {
+    let tz = real_time.timezone();
+    let date = real_time.date_naive();
+    let mut candidates = Vec::new();
+    for day in [date.pred_opt(), Some(date), date.succ_opt()].into_iter().flatten() {
+        let local = day.and_time(time);
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(t) => candidates.push(t),
+            // Clocks went back, so this time happened twice. Both are candidates.
+            LocalResult::Ambiguous(earliest, latest) => {
+                candidates.push(earliest);
+                candidates.push(latest);
+            },
+            // Clocks went forward over this time, so the watch hasn't been changed yet and is
+            // still on the offset from before the gap
+            LocalResult::None => {
+                let before = tz.from_local_datetime(&(local - chrono::TimeDelta::hours(3)))
+                    .earliest()
+                    .unwrap();
+                let offset = before.offset().fix();
+                candidates.push(tz.from_utc_datetime(&(local - offset)));
+            },
+        }
+    }
+    candidates.into_iter()
+        .min_by_key(|t| t.clone().signed_duration_since(real_time.clone()).abs())
+        .unwrap()
 }
 
-fn get_matching_watches(query: &str) -> Vec<Watch> {
-    let re = RegexBuilder::new(&query)
+// `query` is a case-insensitive regex on the name, or `@<id>` to match on (a prefix of) the ID.
+// Archived watches are skipped unless `all` is set.
+fn get_matching_watches(query: &str, all: bool) -> Vec<Watch> {
+    let watches = load_file().into_iter().filter(|w| all || !w.archived);
+    if let Some(id) = query.strip_prefix('@') {
+        let id = id.to_lowercase();
+        return watches.filter(|w| w.id.starts_with(&id)).collect();
+    }
+    let re = RegexBuilder::new(query)
         .case_insensitive(true)
         .build()
         .unwrap();
-    let watches = load_file();
     let mut matching = Vec::new();
     for w in watches {
         if re.is_match(&w.name) {
@@ -327,86 +1472,878 @@ fn get_matching_watches(query: &str) -> Vec<Watch> {
     }
     matching
 }
+fn resolve_db_path(cli_path: Option<PathBuf>) -> PathBuf {
+    // clap has already folded WD_DB into `cli_path` if the flag wasn't given
+    if let Some(path) = cli_path.or_else(|| config().db.clone()) {
+        return path;
+    }
+    let mut data_dir = match dirs::data_dir() {
+        Some(path) => path,
+        None => {
+            println!("Couldn't determine the data directory. Pass --db, set WD_DB, or set `db` in the config.");
+            std::process::exit(1);
+        }
+    };
+    data_dir.push("wd");
+    data_dir.push("watches.json");
+
+    // Before the path was configurable the database always lived here. Bring it over once so an
+    // upgrade doesn't start from an empty database.
+    if let Some(legacy) = dirs::home_dir().map(|home| home.join(LEGACY_DB_PATH)) {
+        if legacy.is_file() && !data_dir.exists() {
+            let copy = || -> io::Result<u64> {
+                fs::create_dir_all(data_dir.parent().unwrap())?;
+                fs::copy(&legacy, &data_dir)
+            };
+            match copy() {
+                Ok(_) => eprintln!("Copied the database from its old location [{:?}] to [{:?}], which is used from now on", legacy, data_dir),
+                Err(e) => {
+                    eprintln!("Using the database at its old location [{:?}], since it couldn't be copied to [{:?}]: {}", legacy, data_dir, e);
+                    return legacy;
+                },
+            }
+        }
+    }
+    data_dir
+}
 fn get_path() -> PathBuf {
-   let mut home_dir = match dirs::home_dir() {
+    DB_PATH.get().expect("database path not resolved").clone()
+}
+fn get_config_path() -> PathBuf {
+    let mut config_dir = match dirs::config_dir() {
         Some(path) => path,
         None => {
-            println!("Couldn't determine the home directory.");
-            panic!();
+            println!("Couldn't determine the config directory.");
+            std::process::exit(1);
         }
     };
-    home_dir.push(PATH);
-    home_dir
+    config_dir.push("wd");
+    config_dir.push("config.toml");
+    config_dir
+}
+fn config() -> &'static Config {
+    CONFIG.get().expect("config not loaded")
+}
+fn read_config_table(path: &PathBuf) -> toml::Table {
+    try_read_config_table(path).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(1);
+    })
+}
+fn try_read_config_table(path: &PathBuf) -> Result<toml::Table, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(toml::Table::new()),
+        Err(e) => panic!("Can't read config [{:?}]: {}", path, e),
+    };
+    toml::from_str(&contents).map_err(|e| format!("Couldn't parse config [{:?}]: {}", path, e))
+}
+fn write_config_table(path: &Path, table: &toml::Table) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, toml::to_string_pretty(table).unwrap()).unwrap();
+}
+fn load_config() -> Config {
+    try_load_config().unwrap_or_else(|e| {
+        println!("{}", e);
+        println!("Fix it with `wd config set`, or by editing the file.");
+        std::process::exit(1);
+    })
+}
+fn try_load_config() -> Result<Config, String> {
+    let path = get_config_path();
+    try_read_config_table(&path)?
+        .try_into()
+        .map_err(|e: toml::de::Error| format!("Invalid config [{:?}]: {}", path, e.message()))
+}
+// Set a dotted `key` like `sntp.server`, creating tables along the way
+fn set_config_value(table: &mut toml::Table, key: &str, value: toml::Value) {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap();
+    let mut t = table;
+    for part in parts {
+        t = t.entry(part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .unwrap_or_else(|| {
+                println!("Config key [{}] is not a table", part);
+                std::process::exit(1);
+            });
+    }
+    t.insert(last.to_owned(), value);
+}
+fn get_config_value<'a>(value: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.').try_fold(value, |v, k| v.get(k))
+}
+// Interpret `s` as a TOML value (number, bool, ...), falling back to a bare string
+fn parse_config_value(s: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("v = {}", s))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(s.to_owned()))
 }
 fn load_file() -> Vec<Watch> {
+    match read_file() {
+        Some(contents) => parse_database(&contents).0.watches,
+        None => Vec::new(),
+    }
+}
+// Returns the raw database contents, or None if nothing has been saved yet
+fn read_file() -> Option<String> {
     let path = get_path();
-    let file = File::open(&path).unwrap_or_else(|_| {
-        println!("No file at path, creating [{:?}]", path);
-        File::create(&path).expect(&format!("Can't create [{:?}]", path))
-    });
-    let reader = io::BufReader::new(file);
-    let watches = serde_json::from_reader(reader).unwrap();
-    watches
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        // Nothing saved yet. The file gets created on the first save.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            println!("Can't read database [{:?}]: {}", path, e);
+            std::process::exit(1);
+        },
+    };
+    if contents.trim().is_empty() {
+        return None;
+    }
+    Some(contents)
+}
+// Parses the database, upgrading it to `SCHEMA_VERSION` in memory if it's older. Also returns the
+// descriptions of the migrations that were applied.
+fn parse_database(contents: &str) -> (Database, Vec<String>) {
+    match try_parse_database(contents) {
+        Ok(parsed) => parsed,
+        Err(DatabaseError::Corrupt(e)) => report_corrupt(e),
+        Err(DatabaseError::NoVersion) => {
+            println!("Database [{:?}] is not a watch database (no schema_version)", get_path());
+            std::process::exit(1);
+        },
+        Err(DatabaseError::TooNew(version)) => {
+            println!("Database [{:?}] uses schema version {}, but this wd only understands up to {}.",
+                get_path(), version, SCHEMA_VERSION);
+            println!("Update wd to read it.");
+            std::process::exit(1);
+        },
+    }
+}
+// Parse the database file, running any migrations it needs. Returns the migrations applied.
+fn try_parse_database(contents: &str) -> Result<(Database, Vec<String>), DatabaseError> {
+    let mut value: serde_json::Value = serde_json::from_str(contents).map_err(DatabaseError::Corrupt)?;
+    let version = get_schema_version(&value).ok_or(DatabaseError::NoVersion)?;
+    if version > SCHEMA_VERSION {
+        return Err(DatabaseError::TooNew(version));
+    }
+    if version == SCHEMA_VERSION {
+        // Deserialize from the text rather than `value` so errors keep their line and column
+        let db = serde_json::from_str(contents).map_err(DatabaseError::Corrupt)?;
+        return Ok((db, Vec::new()));
+    }
+
+    let mut applied = Vec::new();
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let notes = (migration.apply)(&mut value);
+        value["schema_version"] = serde_json::Value::from(from + 1);
+        applied.push(format!("v{} -> v{}: {}", from, from + 1, migration.description));
+        applied.extend(notes.into_iter().map(|note| format!("  {}", note)));
+    }
+    let db = serde_json::from_value(value).map_err(DatabaseError::Corrupt)?;
+    Ok((db, applied))
+}
+fn get_schema_version(value: &serde_json::Value) -> Option<u32> {
+    match value {
+        // Before the envelope, the file was a bare array of watches
+        serde_json::Value::Array(_) => Some(0),
+        serde_json::Value::Object(o) => o.get("schema_version")?.as_u64().map(|v| v as u32),
+        _ => None,
+    }
+}
+fn report_corrupt(e: serde_json::Error) -> ! {
+    let path = get_path();
+    // Errors from an in-memory migration have no position in the file
+    if e.line() > 0 {
+        println!("Database [{:?}] is corrupt at line {}, column {}:", path, e.line(), e.column());
+    } else {
+        println!("Database [{:?}] is corrupt:", path);
+    }
+    println!("  {}", e);
+    let backup = get_backup_path(1);
+    if backup.exists() {
+        println!("The previous version is at [{:?}]. To restore it:", backup);
+        println!("  cp {:?} {:?}", backup, path);
+    } else {
+        println!("Fix the file by hand or restore it from a backup.");
+    }
+    std::process::exit(1);
+}
+fn migrate_v0_envelope(value: &mut serde_json::Value) -> Vec<String> {
+    let watches = value.take();
+    *value = serde_json::json!({ "watches": watches });
+    Vec::new()
+}
+fn migrate_v1_watch_ids(value: &mut serde_json::Value) -> Vec<String> {
+    // Read-only commands don't save the migrated file, so derive IDs from the existing data to
+    // keep them the same from one run to the next until it's rewritten
+    let mut taken: Vec<String> = Vec::new();
+    for (idx, w) in value["watches"].as_array_mut().unwrap().iter_mut().enumerate() {
+        let seed = format!("{}:{}", idx, w["name"].as_str().unwrap_or_default());
+        let mut id = format!("{:06x}", fnv1a(seed.as_bytes()) & 0xffffff);
+        while taken.contains(&id) {
+            id = format!("{:06x}", fnv1a(id.as_bytes()) & 0xffffff);
+        }
+        w["id"] = serde_json::Value::from(id.clone());
+        taken.push(id);
+    }
+    Vec::new()
+}
+fn migrate_v2_utc_readings(value: &mut serde_json::Value) -> Vec<String> {
+    let to_utc = |t: &serde_json::Value| {
+        let t = DateTime::parse_from_rfc3339(t.as_str()?).ok()?;
+        Some((t.with_timezone(&Utc), t.offset().local_minus_utc()))
+    };
+    for w in value["watches"].as_array_mut().unwrap() {
+        let Some(measures) = w["measures"].as_array_mut() else { continue };
+        for m in measures {
+            for key in ["measure_start", "measure_end"] {
+                let Some(pair) = m.get_mut(key).and_then(|p| p.as_object_mut()) else { continue };
+                // Readings were stored in local time, so the watch was on the local offset
+                if let Some((watch_time, offset)) = pair.get("watch_time").and_then(to_utc) {
+                    pair.insert("watch_time".to_owned(), serde_json::json!(watch_time));
+                    pair.insert("watch_offset".to_owned(), serde_json::json!(offset));
+                }
+                if let Some((real_time, _)) = pair.get("real_time").and_then(to_utc) {
+                    pair.insert("real_time".to_owned(), serde_json::json!(real_time));
+                }
+            }
+        }
+    }
+    Vec::new()
+}
+fn migrate_v3_movement_kinds(value: &mut serde_json::Value) -> Vec<String> {
+    for w in value["watches"].as_array_mut().unwrap() {
+        let kind = match w["movement"].as_str() {
+            Some("Mechanical") => "automatic",
+            Some("Quartz") => "quartz",
+            Some("Smart") => "smart",
+            _ => continue,
+        };
+        w["movement"] = serde_json::json!(kind);
+    }
+    Vec::new()
+}
+fn migrate_v4_unit_free_rates(value: &mut serde_json::Value) -> Vec<String> {
+    let mut notes = Vec::new();
+    for w in value["watches"].as_array_mut().unwrap() {
+        let movement = serde_json::from_value::<Movement>(w["movement"].clone()).ok();
+        let name = w["name"].as_str().unwrap_or("?").to_owned();
+        let Some(measures) = w["measures"].as_array_mut() else { continue };
+        for m in measures {
+            let Some(obj) = m.as_object_mut() else { continue };
+            let drift = obj.remove("drift").and_then(|v| v.as_f64());
+            let uncertainty = obj.remove("uncertainty").and_then(|v| v.as_f64());
+            if drift.is_none() {
+                continue;
+            }
+            // Drift was stored in whatever unit was configured back then, which may have changed
+            // since, so work the rate out again from the readings
+            let refit = serde_json::from_value::<Measure>(m.clone()).ok()
+                .filter(|measure| measure.measure_start.is_some() && measure.measure_end.is_some())
+                .and_then(|measure| measure.fit().map(|fit| (fit.slope, measure.rate_uncertainty(&fit))));
+            let (rate, rate_sd) = match (refit, &movement) {
+                (Some((rate, sd)), _) => (Some(rate), Some(sd)),
+                (None, Some(movement)) => {
+                    let unit = movement.rate_unit();
+                    notes.push(format!("A measure of [{}] is missing readings, so its drift is taken to be in {}, the unit configured now",
+                        name, unit.to_str()));
+                    (drift.map(|v| unit.to_rate(v)), uncertainty.map(|v| unit.to_rate(v)))
+                },
+                (None, None) => continue,
+            };
+            let obj = m.as_object_mut().unwrap();
+            if let Some(rate) = rate {
+                obj.insert("rate".to_owned(), serde_json::json!(rate));
+            }
+            if let Some(rate_sd) = rate_sd {
+                obj.insert("rate_sd".to_owned(), serde_json::json!(rate_sd));
+            }
+        }
+    }
+    notes
+}
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+// Short random hex ID, unique among `taken`
+fn new_watch_id(taken: &[String]) -> String {
+    loop {
+        // Every RandomState is seeded differently, which is plenty for a short ID
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos());
+        let id = format!("{:06x}", hasher.finish() & 0xffffff);
+        if !taken.contains(&id) {
+            return id;
+        }
+    }
+}
+// Write back changed watches in a single save, so a command touching several watches only takes up
+// one backup
+fn save_watches(changed: &[Watch]) {
+    let mut watches = load_file();
+    for watch in changed {
+        if config().print_on_save {
+            println!("Saving watch: {:#?}", watch);
+            if let Some(m) = watch.measures.last() {
+                println!("Latest measure:\n{}", m.in_unit(watch.rate_unit()));
+            }
+        }
+        match watches.iter_mut().find(|w| w.id == watch.id) {
+            Some(w) => *w = watch.clone(),
+            None => {
+                println!("Adding new watch entry");
+                watches.push(watch.clone());
+            },
+        }
+    }
+
+    save_file(watches)
 }
 fn save_file(w: Vec<Watch>) {
     let path = get_path();
-    let file = File::create(&path).unwrap();
-    let writer = io::BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &w).unwrap();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .unwrap_or_else(|e| panic!("Can't create [{:?}]: {}", parent, e));
+    }
+    let db = Database {
+        schema_version: SCHEMA_VERSION,
+        watches: w,
+    };
+    if let Err(e) = write_file_atomic(&path, &db) {
+        println!("Failed to save [{:?}]: {}", path, e);
+        println!("The database was left unchanged.");
+        std::process::exit(1);
+    }
+}
+// Write to a temp file next to `path`, then swap it in with a rename so a crash mid-write can't
+// truncate the live database. The version being replaced becomes backup 1.
+fn write_file_atomic(path: &Path, db: &Database) -> io::Result<()> {
+    let tmp_path = path_with_suffix(path, ".tmp");
+    let write_tmp = || -> io::Result<()> {
+        let file = File::create(&tmp_path)?;
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, db)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()
+    };
+    if let Err(e) = write_tmp() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    if path.exists() {
+        if let Err(e) = back_up(path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    }
+    fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable. Directories can't be opened for syncing on every
+    // platform, so this is best effort.
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+// Shift `<db>.bak.1`..`<db>.bak.N-1` up one, dropping the oldest, and copy the live database in as
+// `<db>.bak.1`. The copy goes through a temp file too, so a backup is never half written.
+fn back_up(path: &Path) -> io::Result<()> {
+    let generations = config().backups;
+    if generations == 0 {
+        return Ok(());
+    }
+    let tmp_path = path_with_suffix(path, ".bak.tmp");
+    let copy_tmp = || -> io::Result<()> {
+        fs::copy(path, &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()
+    };
+    if let Err(e) = copy_tmp() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    for n in (1..generations).rev() {
+        let from = get_backup_path(n);
+        if from.exists() {
+            fs::rename(&from, get_backup_path(n + 1))?;
+        }
+    }
+    fs::rename(&tmp_path, get_backup_path(1))
+}
+fn lock_db(no_wait: bool) -> File {
+    let db_path = get_path();
+    let path = path_with_suffix(&db_path, ".lock");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .unwrap_or_else(|e| panic!("Can't create [{:?}]: {}", parent, e));
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .unwrap_or_else(|e| panic!("Can't open lock file [{:?}]: {}", path, e));
+
+    match file.try_lock() {
+        Ok(()) => return file,
+        Err(fs::TryLockError::WouldBlock) => {},
+        Err(fs::TryLockError::Error(e)) => panic!("Can't lock [{:?}]: {}", path, e),
+    }
+    if no_wait {
+        println!("Database [{:?}] is in use by another wd process", db_path);
+        std::process::exit(1);
+    }
+    println!("Waiting for another wd process to finish with [{:?}]... (^C to cancel)", db_path);
+    file.lock().unwrap_or_else(|e| panic!("Can't lock [{:?}]: {}", path, e));
+    file
+}
+// Backup `n` is the version from `n` saves ago
+fn get_backup_path(n: u32) -> PathBuf {
+    path_with_suffix(&get_path(), &format!(".bak.{}", n))
+}
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    s.into()
 }
 
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Database {
+    schema_version: u32,
+    watches: Vec<Watch>,
+}
+#[derive(Debug)]
+enum DatabaseError {
+    Corrupt(serde_json::Error),
+    NoVersion,
+    /// Written by a newer wd
+    TooNew(u32),
+}
+struct Migration {
+    description: &'static str,
+    /// Returns notes on anything the user should know about the upgraded data
+    apply: fn(&mut serde_json::Value) -> Vec<String>,
+}
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Watch {
+    id: String,
     name: String,
     movement: Movement,
+    /// Unit to report the rate in, instead of the movement's default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit: Option<RateUnit>,
+    /// Manufacturer's accuracy specification
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spec: Option<Spec>,
     logs: Vec<NaiveDate>,
     measures: Vec<Measure>,
+    /// Times the watch was set, with the offset it was set to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sets: Vec<WatchTimePair>,
+    /// IANA timezone the watch is kept on, if not the system's (e.g. a GMT watch left on home time)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    watch_tz: Option<String>,
+    /// Hidden from listings and selection unless --all is passed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    archived: bool,
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Measure {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    drift: Option<f64>,
+    /// Seconds gained per second, positive when the watch runs fast. See `RateUnit` to convert.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rate: Option<f64>,
+    /// One standard deviation of `rate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rate_sd: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     measure_start: Option<WatchTimePair>,
+    /// Intermediate readings between the start and end, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<WatchTimePair>,
     #[serde(skip_serializing_if = "Option::is_none")]
     measure_end: Option<WatchTimePair>,
+    /// Set when the measure is one position of a `wd position-test`, rather than worn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    leg: Option<PositionLeg>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PositionLeg {
+    /// Numbered from 1 per watch, so the legs of a test can be told apart from earlier tests
+    test: u32,
+    position: Position,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Position {
+    DialUp,
+    DialDown,
+    CrownUp,
+    CrownDown,
+    CrownLeft,
+    CrownRight,
+}
+// A manufacturer's accuracy specification, e.g. -4/+6 s/day for COSC
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Spec {
+    min: f64,
+    max: f64,
+    unit: RateUnit,
+    /// Preset it came from, e.g. COSC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+enum SpecStatus {
+    Within,
+    Outside,
+    // Not measured, no spec, or too close to the edge to tell
+    Unknown,
+}
+// Rates are unit-free until converted with `in_unit`
+struct DriftSummary {
+    count: usize,
+    // Total length of the measures
+    days: f64,
+    // Weighted by measure length, as is the standard deviation
+    mean: f64,
+    // Standard deviation of the mean, from the scatter or the measures' own uncertainty
+    mean_sd: f64,
+    median: f64,
+    std_dev: f64,
+    min: f64,
+    max: f64,
+}
+struct LinearFit {
+    slope: f64,
+    intercept: f64,
+    // Spread of x, sum((x - mean(x))²), for the standard error of the slope
+    sxx: f64,
+    // Standard deviation of the residuals, with at least three points
+    residual_sd: Option<f64>,
+    r_squared: Option<f64>,
+    residuals: Vec<f64>,
 }
 struct WatchBuilder {
     name: Option<String>,
     movement: Option<Movement>,
+    watch_tz: Option<Tz>,
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct WatchTimePair {
-    watch_time: DateTime<Local>,
-    real_time: DateTime<Local>,
+    /// Instant the watch was showing
+    watch_time: DateTime<Utc>,
+    real_time: DateTime<Utc>,
+    /// UTC offset of the time shown on the watch, in seconds
+    watch_offset: i32,
+    /// IANA timezone of the system clock, if it could be determined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_tz: Option<String>,
+    #[serde(default)]
+    precision: TimePrecision,
+    /// Seconds the system clock was behind the SNTP server when the reading was taken. Unknown if
+    /// the server couldn't be reached, the check is turned off, or the time was given explicitly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock_offset: Option<f64>,
+    /// Round trip to the SNTP server, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock_delay: Option<f64>,
+}
+// A reference time from the command line. `now` is read off the system clock like an interactive
+// reading, so it gets checked against the SNTP server too.
+#[derive(Clone, Copy, Debug)]
+enum RealTime {
+    Now(DateTime<Utc>),
+    At(DateTime<Utc>),
+}
+// How finely the watch time was read off the dial
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum TimePrecision {
+    /// Whole minutes. Relies on the reading being taken exactly at the watch's :00.
+    #[default]
+    Minute,
+    /// Seconds were read off the dial too
+    Second,
 }
 #[derive(Serialize, Deserialize, clap::ValueEnum, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
 enum Movement {
+    /// Self-winding mechanical
+    #[value(alias = "mechanical")]
+    Automatic,
+    /// Manually wound mechanical
+    HandWound,
+    /// Mechanical, regulated by a quartz crystal
+    SpringDrive,
+    /// Battery powered quartz
     Quartz,
-    Mechanical,
+    /// Thermocompensated quartz, rated in seconds per year
+    #[value(name = "haq", alias = "thermo-quartz")]
+    #[serde(rename = "haq")]
+    HighAccuracyQuartz,
+    /// Solar powered quartz
+    Solar,
+    /// Quartz that syncs to a radio time signal
+    Radio,
+    /// Quartz that syncs to GPS satellites
+    Gps,
+    /// Smartwatch, synced to a phone
     Smart,
 }
-impl Movement {
-    // unit returns milliseconds per unit (day or month)
-    fn unit(&self) -> i64 {
+#[derive(Serialize, Deserialize, clap::ValueEnum, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum RateUnit {
+    /// Seconds per day
+    Day,
+    /// Seconds per week
+    Week,
+    /// Seconds per month (365/12 days)
+    Month,
+    /// Seconds per year (365 days)
+    Year,
+    /// Parts per million
+    Ppm,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum TableSort {
+    Wears,
+    Name,
+    Drift,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Config {
+    /// Database path, used when neither --db nor WD_DB is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    db: Option<PathBuf>,
+    /// Seconds added to the real time to pre-fill the watch time dialog
+    watch_time_lead_secs: i64,
+    /// Row order of the `print` table
+    table_sort: TableSort,
+    /// Dump the full watch record every time it's saved
+    print_on_save: bool,
+    /// Previous versions of the database to keep, as `<db>.bak.1` (newest) to `<db>.bak.N`
+    backups: u32,
+    /// How late you press [Enter] on average, in seconds. Taken off every reading.
+    reaction_latency_secs: f64,
+    /// Standard deviation of your reaction time when pressing [Enter], in seconds
+    reaction_jitter_secs: f64,
+    /// Countdown to the next minute while waiting for [Enter] at :00
+    countdown: Countdown,
+    /// Server the system clock is checked against with each reading
+    sntp: Sntp,
+    /// Legs of `wd position-test`
+    position_test: PositionTestConfig,
+    /// Unit drift is reported in, per movement
+    units: Units,
+    /// Default band for `wd trend`, per movement, as [min, max] in that movement's unit
+    tolerance: Tolerances,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Countdown {
+    Off,
+    Visual,
+    // Rings the terminal bell
+    Audible,
+    Both,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct PositionTestConfig {
+    /// How long to leave the watch in each position
+    hours: f64,
+    /// Positions to test, in order
+    positions: Vec<Position>,
+    /// How long the watch rests overnight, for the resting position suggestion
+    night_hours: f64,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Sntp {
+    /// Host, with an optional :port, e.g. pool.ntp.org. Empty (the default) turns the check off.
+    server: String,
+    /// How long to wait for a reply, in milliseconds
+    timeout_ms: u64,
+}
+// Keyed by movement. `mechanical` is still read as `automatic`, from before the kinds were split.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Units {
+    #[serde(alias = "mechanical")]
+    automatic: RateUnit,
+    hand_wound: RateUnit,
+    spring_drive: RateUnit,
+    quartz: RateUnit,
+    haq: RateUnit,
+    solar: RateUnit,
+    radio: RateUnit,
+    gps: RateUnit,
+    smart: RateUnit,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Tolerances {
+    #[serde(alias = "mechanical")]
+    automatic: [f64; 2],
+    hand_wound: [f64; 2],
+    spring_drive: [f64; 2],
+    quartz: [f64; 2],
+    haq: [f64; 2],
+    solar: [f64; 2],
+    radio: [f64; 2],
+    gps: [f64; 2],
+    smart: [f64; 2],
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            db: None,
+            watch_time_lead_secs: 55,
+            table_sort: TableSort::Wears,
+            print_on_save: true,
+            backups: 5,
+            reaction_latency_secs: 0.0,
+            reaction_jitter_secs: 0.5,
+            countdown: Countdown::Off,
+            sntp: Sntp::default(),
+            position_test: PositionTestConfig::default(),
+            units: Units::default(),
+            tolerance: Tolerances::default(),
+        }
+    }
+}
+impl Default for PositionTestConfig {
+    fn default() -> Self {
+        PositionTestConfig {
+            hours: 8.0,
+            positions: vec![Position::DialUp, Position::DialDown, Position::CrownUp,
+                Position::CrownDown, Position::CrownLeft, Position::CrownRight],
+            night_hours: 8.0,
+        }
+    }
+}
+impl Default for Sntp {
+    fn default() -> Self {
+        // Off until a server is chosen, so readings don't reach out to the network unasked
+        Sntp {
+            server: String::new(),
+            timeout_ms: 1000,
+        }
+    }
+}
+impl Default for Tolerances {
+    fn default() -> Self {
+        Tolerances {
+            automatic: [-10.0, 10.0],
+            hand_wound: [-10.0, 10.0],
+            spring_drive: [-15.0, 15.0],
+            quartz: [-15.0, 15.0],
+            haq: [-10.0, 10.0],
+            solar: [-15.0, 15.0],
+            // Between syncs
+            radio: [-15.0, 15.0],
+            gps: [-15.0, 15.0],
+            smart: [-1.0, 1.0],
+        }
+    }
+}
+impl Default for Units {
+    fn default() -> Self {
+        Units {
+            automatic: RateUnit::Day,
+            hand_wound: RateUnit::Day,
+            spring_drive: RateUnit::Month,
+            quartz: RateUnit::Month,
+            haq: RateUnit::Year,
+            solar: RateUnit::Month,
+            radio: RateUnit::Month,
+            gps: RateUnit::Month,
+            smart: RateUnit::Month,
+        }
+    }
+}
+impl RateUnit {
+    // How many of this unit a rate of one second per second is
+    fn scale(self) -> f64 {
         match self {
-            Movement::Quartz => 2628000000,
-            Movement::Mechanical => 86400000,
-            Movement::Smart => 2628000000,
+            RateUnit::Day => 86400.0,
+            RateUnit::Week => 604800.0,
+            RateUnit::Month => 2628000.0,
+            RateUnit::Year => 31536000.0,
+            RateUnit::Ppm => 1e6,
         }
     }
-    fn unit_str(&self) -> &str {
+    // Unit-free rate (s/s) to this unit, and back
+    fn convert(self, rate: f64) -> f64 {
+        rate * self.scale()
+    }
+    fn to_rate(self, value: f64) -> f64 {
+        value / self.scale()
+    }
+    fn to_str(self) -> &'static str {
+        match self {
+            RateUnit::Day => "s/day",
+            RateUnit::Week => "s/week",
+            RateUnit::Month => "s/month",
+            RateUnit::Year => "s/year",
+            RateUnit::Ppm => "ppm",
+        }
+    }
+}
+impl Movement {
+    fn rate_unit(&self) -> RateUnit {
+        let units = &config().units;
         match self {
-            Movement::Quartz => "month",
-            Movement::Smart => "month",
-            Movement::Mechanical => "day",
+            Movement::Automatic => units.automatic,
+            Movement::HandWound => units.hand_wound,
+            Movement::SpringDrive => units.spring_drive,
+            Movement::Quartz => units.quartz,
+            Movement::HighAccuracyQuartz => units.haq,
+            Movement::Solar => units.solar,
+            Movement::Radio => units.radio,
+            Movement::Gps => units.gps,
+            Movement::Smart => units.smart,
         }
     }
-    fn to_str(&self) -> &str {
+    // Default (min, max) rate for `wd trend`, in `rate_unit()`
+    fn tolerance(&self) -> (f64, f64) {
+        let tolerance = &config().tolerance;
+        let [min, max] = match self {
+            Movement::Automatic => tolerance.automatic,
+            Movement::HandWound => tolerance.hand_wound,
+            Movement::SpringDrive => tolerance.spring_drive,
+            Movement::Quartz => tolerance.quartz,
+            Movement::HighAccuracyQuartz => tolerance.haq,
+            Movement::Solar => tolerance.solar,
+            Movement::Radio => tolerance.radio,
+            Movement::Gps => tolerance.gps,
+            Movement::Smart => tolerance.smart,
+        };
+        (min, max)
+    }
+    fn to_str(&self) -> &'static str {
         match self {
+            Movement::Automatic => "Automatic",
+            Movement::HandWound => "Hand-wound",
+            Movement::SpringDrive => "Spring Drive",
             Movement::Quartz => "Quartz",
-            Movement::Mechanical => "Mechanical",
+            Movement::HighAccuracyQuartz => "HAQ quartz",
+            Movement::Solar => "Solar",
+            Movement::Radio => "Radio",
+            Movement::Gps => "GPS",
             Movement::Smart => "Smart",
         }
     }
@@ -414,54 +2351,158 @@ impl Movement {
 
 impl Watch {
     fn new() -> Self {
+        let taken: Vec<String> = load_file().into_iter().map(|w| w.id).collect();
         Watch {
+            id: new_watch_id(&taken),
             name: String::new(),
             movement: Movement::Quartz,
+            unit: None,
+            spec: None,
             logs: Vec::new(),
             measures: Vec::new(),
+            sets: Vec::new(),
+            watch_tz: None,
+            archived: false,
         }
     }
 
     fn save(&self) {
-        println!("Saving watch: {:#?}", self);
+        save_watches(std::slice::from_ref(self))
+    }
+    fn delete(&self) {
         let mut watches = load_file();
-        let mut found = false;
-        for w in &mut watches {
-            if w.name == self.name {
-                found = true;
-                *w = self.clone();
-                break;
-            }
-        }
-        if !found {
-            println!("Adding new watch entry");
-            watches.push(self.clone());
-        }
-
+        watches.retain(|w| w.id != self.id);
         save_file(watches)
     }
 
+    fn tz(&self) -> Option<Tz> {
+        self.watch_tz.as_ref().and_then(|tz| tz.parse().ok())
+    }
     fn measure_start(&self) -> Option<WatchTimePair> {
         if let Some(m) = self.measures.last() {
             return m.measure_start.clone();
         }
         None
     }
-    fn drift(&self) -> Option<f64> {
-        if let Some(m) = self.measures.last() {
-            return m.drift.clone();
+    fn rate(&self) -> Option<f64> {
+        self.last_complete_measure().and_then(|m| m.rate)
+    }
+    // The unit to report this watch's rate in
+    fn rate_unit(&self) -> RateUnit {
+        self.unit.unwrap_or_else(|| self.movement.rate_unit())
+    }
+    // The watch's spec, or else the movement's default tolerance, as (min, max) in `rate_unit()`
+    fn tolerance(&self) -> (f64, f64) {
+        let (min, max) = match &self.spec {
+            Some(spec) => (spec.unit.to_rate(spec.min), spec.unit.to_rate(spec.max)),
+            None => {
+                let (min, max) = self.movement.tolerance();
+                let unit = self.movement.rate_unit();
+                (unit.to_rate(min), unit.to_rate(max))
+            },
+        };
+        (self.rate_unit().convert(min), self.rate_unit().convert(max))
+    }
+    // Whether the latest measure, or the aggregate, is within the spec
+    fn spec_status(&self, agg: &AggregateArgs) -> SpecStatus {
+        let Some(spec) = &self.spec else { return SpecStatus::Unknown };
+        let measured = if agg.aggregate {
+            self.summary(agg).map(|s| (s.mean, s.mean_sd))
+        } else {
+            self.last_complete_measure().map(|m| (m.rate.unwrap(), m.rate_sd.unwrap_or(0.0)))
+        };
+        match measured {
+            Some((rate, sd)) => spec.status(rate, sd),
+            None => SpecStatus::Unknown,
         }
-        None
     }
+    // The latest measure taken while the watch was worn, i.e. not a position test leg
     fn last_complete_measure(&self) -> Option<&Measure> {
-        for m in self.measures.iter().rev() {
-            if m.measure_start.is_some() &&
-               m.measure_end.is_some() &&
-               m.drift.is_some() {
-                   return Some(m);
-            }
+        self.measures.iter().rev().find(|m| {
+            m.measure_start.is_some() &&
+            m.measure_end.is_some() &&
+            m.rate.is_some() &&
+            m.leg.is_none()
+        })
+    }
+    // Exits if a position test leg is running, since other measures would get mixed up with it
+    fn require_no_active_leg(&self) {
+        if self.measures.last().is_some_and(|m| m.leg.is_some() && m.measure_end.is_none()) {
+            println!("[{}] is in the middle of a position test. Continue it with `wd position-test`, or start over with `wd position-test --restart`.", self.name);
+            std::process::exit(1);
         }
-        None
+    }
+
+    // The watch's offset from real time at `now` (s), projected from the latest reading or setting
+    // at the latest measured rate. Returns when that was, the offset, and its standard deviation,
+    // which is None if the rate isn't known yet and the offset is just the last one seen.
+    fn estimated_offset(&self, now: DateTime<Utc>) -> Option<(DateTime<Utc>, f64, Option<f64>)> {
+        let latest = self.measures.iter()
+            .flat_map(|m| m.readings())
+            .chain(self.sets.iter())
+            .max_by_key(|r| r.real_time)?;
+        let Some(m) = self.last_complete_measure() else {
+            return Some((latest.real_time, latest.offset_secs(), None));
+        };
+        let elapsed = now.signed_duration_since(latest.real_time).num_seconds() as f64;
+        let offset = latest.offset_secs() + m.rate.unwrap() * elapsed;
+        let rate_sigma = m.rate_sd.unwrap_or(0.0) * elapsed;
+        Some((latest.real_time, offset, Some((latest.sigma().powi(2) + rate_sigma.powi(2)).sqrt())))
+    }
+
+    // Statistics over the completed measures selected by `agg`
+    fn summary(&self, agg: &AggregateArgs) -> Option<DriftSummary> {
+        let mut measures: Vec<&Measure> = self.measures.iter()
+            .filter(|m| m.measure_start.is_some() && m.measure_end.is_some() && m.rate.is_some() && m.leg.is_none())
+            .filter(|m| {
+                let end = m.measure_end.as_ref().unwrap().real_time.with_timezone(&Local).date_naive();
+                agg.since.is_none_or(|since| end >= since) && agg.until.is_none_or(|until| end <= until)
+            })
+            .collect();
+        if let Some(last) = agg.last {
+            measures = measures.split_off(measures.len().saturating_sub(last));
+        }
+        if measures.is_empty() {
+            return None;
+        }
+
+        // Weight each measure by how long it ran, since longer measures are more accurate
+        let drifts: Vec<(f64, f64)> = measures.iter().map(|m| {
+            let duration = m.measure_end.as_ref().unwrap().real_time
+                .signed_duration_since(m.measure_start.as_ref().unwrap().real_time);
+            (m.rate.unwrap(), duration.num_seconds() as f64)
+        }).collect();
+        let total_weight: f64 = drifts.iter().map(|d| d.1).sum();
+        if total_weight <= 0.0 {
+            return None;
+        }
+        let mean = drifts.iter().map(|d| d.0 * d.1).sum::<f64>() / total_weight;
+        let variance = drifts.iter().map(|d| (d.0 - mean).powi(2) * d.1).sum::<f64>() / total_weight;
+
+        let mut sorted: Vec<f64> = drifts.iter().map(|d| d.0).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let mid = sorted.len() / 2;
+        let median = match sorted.len() % 2 {
+            0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+            _ => sorted[mid],
+        };
+
+        // The mean is only as good as the measures' scatter or their own uncertainty, whichever is worse
+        let scatter = variance.sqrt() / (drifts.len() as f64).sqrt();
+        let measured = measures.iter().zip(&drifts)
+            .map(|(m, d)| (m.rate_sd.unwrap_or(0.0) * d.1).powi(2))
+            .sum::<f64>().sqrt() / total_weight;
+
+        Some(DriftSummary {
+            count: sorted.len(),
+            days: total_weight / 86400.0,
+            mean,
+            mean_sd: scatter.max(measured),
+            median,
+            std_dev: variance.sqrt(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+        })
     }
 
     fn update_running(&mut self) {
@@ -469,19 +2510,37 @@ impl Watch {
             if m.measure_start.is_none() || m.measure_end.is_none() {
                 continue;
             }
-            let real_time_start  = m.measure_start.as_ref().unwrap().real_time;
-            let watch_time_start = m.measure_start.as_ref().unwrap().watch_time;
-            let real_time_end    = m.measure_end.as_ref().unwrap().real_time;
-            let watch_time_end   = m.measure_end.as_ref().unwrap().watch_time;
-
-            let real_time_passed = real_time_end.signed_duration_since(real_time_start);
-            let watch_time_passed = watch_time_end.signed_duration_since(watch_time_start);
-            let duration_diff = watch_time_passed.num_milliseconds() - real_time_passed.num_milliseconds();
-            let diff_per_unit = (duration_diff * self.movement.unit()) as f64 / real_time_passed.num_milliseconds() as f64;
-            m.drift = Some(diff_per_unit.round() / 1000.0);
+            // The rate is the slope of the watch's offset over time, which for just a start and
+            // end is (watch time passed - real time passed) / real time passed
+            let Some(fit) = m.fit() else {
+                m.rate = None;
+                m.rate_sd = None;
+                continue;
+            };
+            m.rate = Some(fit.slope);
+            m.rate_sd = Some(m.rate_uncertainty(&fit));
         }
     }
 
+    fn table_print_drift(&self, agg: &AggregateArgs, units: Option<RateUnit>) -> String {
+        let unit = units.unwrap_or(self.rate_unit());
+        if agg.aggregate {
+            return match self.summary(agg) {
+                Some(s) => {
+                    let s = s.in_unit(unit);
+                    format!("{:+.2} {} (std dev {:.2}), ({} measures)", s.mean, unit.to_str(), s.std_dev, s.count)
+                },
+                None => "??".to_owned(),
+            };
+        }
+        match (self.rate(), self.last_complete_measure()) {
+            (Some(_), Some(m)) => {
+                let (val, units) = m.measure_duration_and_units();
+                format!("{}, ({} {})", format_drift(m.rate.unwrap(), m.rate_sd, unit), val, units)
+            },
+            _ => "??".to_owned(),
+        }
+    }
     fn table_print_name(&self) -> String {
         let mut name = self.name.clone();
 
@@ -490,6 +2549,10 @@ impl Watch {
             name = format!("* {} *", name);
         }
 
+        if self.archived {
+            name = format!("{} (archived)", name);
+        }
+
         // Indicate if we wore this watch today
         if !self.logs.is_empty() && *self.logs.last().unwrap() == Local::now().date_naive() {
             name = format!("--> {} <--", name);
@@ -498,42 +2561,194 @@ impl Watch {
         name
     }
 }
+impl Spec {
+    // Within only if the rate is at least two standard deviations inside the spec, and outside
+    // only if it's two standard deviations past it
+    fn status(&self, rate: f64, rate_sd: f64) -> SpecStatus {
+        let (min, max) = (self.unit.to_rate(self.min), self.unit.to_rate(self.max));
+        let (low, high) = (rate - 2.0 * rate_sd, rate + 2.0 * rate_sd);
+        if low >= min && high <= max {
+            SpecStatus::Within
+        } else if high < min || low > max {
+            SpecStatus::Outside
+        } else {
+            SpecStatus::Unknown
+        }
+    }
+}
+impl std::fmt::Display for Spec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.min == -self.max {
+            write!(f, "±{} {}", self.max, self.unit.to_str())?;
+        } else {
+            write!(f, "{:+}/{:+} {}", self.min, self.max, self.unit.to_str())?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+impl SpecStatus {
+    fn to_str(&self) -> &'static str {
+        match self {
+            SpecStatus::Within => "within spec",
+            SpecStatus::Outside => "out of spec",
+            SpecStatus::Unknown => "unknown",
+        }
+    }
+}
+impl Position {
+    fn to_str(self) -> &'static str {
+        match self {
+            Position::DialUp => "dial up",
+            Position::DialDown => "dial down",
+            Position::CrownUp => "crown up",
+            Position::CrownDown => "crown down",
+            Position::CrownLeft => "crown left",
+            Position::CrownRight => "crown right",
+        }
+    }
+}
+impl DriftSummary {
+    fn in_unit(self, unit: RateUnit) -> DriftSummary {
+        DriftSummary {
+            mean: unit.convert(self.mean),
+            mean_sd: unit.convert(self.mean_sd),
+            median: unit.convert(self.median),
+            std_dev: unit.convert(self.std_dev),
+            min: unit.convert(self.min),
+            max: unit.convert(self.max),
+            ..self
+        }
+    }
+}
 impl Measure {
+    fn in_unit(&self, unit: RateUnit) -> MeasureInUnit<'_> {
+        MeasureInUnit(self, unit)
+    }
+    // All readings, in order
+    fn readings(&self) -> Vec<&WatchTimePair> {
+        self.measure_start.iter()
+            .chain(self.checks.iter())
+            .chain(self.measure_end.iter())
+            .collect()
+    }
+    fn add_check(&mut self, check: WatchTimePair) {
+        self.checks.push(check);
+        self.checks.sort_by_key(|c| c.real_time);
+    }
+    // Fit of the watch's offset from real time (s) against time since the first reading (s)
+    fn fit(&self) -> Option<LinearFit> {
+        let readings = self.readings();
+        let first = readings.first()?.reference_time();
+        let points: Vec<(f64, f64)> = readings.iter().map(|r| {
+            let elapsed = r.reference_time().signed_duration_since(first).num_milliseconds() as f64 / 1000.0;
+            (elapsed, r.offset_secs())
+        }).collect();
+        linear_fit(&points)
+    }
+    // Standard deviation of the fitted rate (s/s). Each reading is off by the reaction time jitter,
+    // plus rounding if the seconds were read off the dial, and that error shrinks the longer the
+    // measure runs. If the readings scatter more than that, the scatter is used instead.
+    fn rate_uncertainty(&self, fit: &LinearFit) -> f64 {
+        let readings = self.readings();
+        let variance = readings.iter().map(|r| r.sigma().powi(2)).sum::<f64>() / readings.len() as f64;
+        let sigma = fit.residual_sd.map_or(variance.sqrt(), |sd| sd.max(variance.sqrt()));
+        sigma / fit.sxx.sqrt()
+    }
     fn measure_duration_and_units(&self) -> (f64, String) {
         let start = self.measure_start.as_ref().unwrap();
         let end = self.measure_end.as_ref().unwrap();
         get_measure_duration_and_units(start.real_time, end.real_time)
     }
 }
-impl std::fmt::Display for Measure {
+impl TimePrecision {
+    // Standard deviation of reading the watch at this precision, in seconds
+    fn sigma(self) -> f64 {
+        match self {
+            // Pressing [Enter] at :00 is all reaction time, which is accounted for separately
+            TimePrecision::Minute => 0.0,
+            // Rounded to the nearest second, uniform over ±0.5s
+            TimePrecision::Second => 0.5 / 3f64.sqrt(),
+        }
+    }
+}
+impl WatchTimePair {
+    // Standard deviation of the watch's measured offset from real time, in seconds. The clock
+    // correction could be off by up to half the round trip either way.
+    fn sigma(&self) -> f64 {
+        let clock = self.clock_delay.unwrap_or(0.0) / 2.0 / 3f64.sqrt();
+        (self.precision.sigma().powi(2) + config().reaction_jitter_secs.powi(2) + clock.powi(2)).sqrt()
+    }
+    // `real_time` corrected for the system clock's error, where it was checked
+    fn reference_time(&self) -> DateTime<Utc> {
+        let offset = self.clock_offset.unwrap_or(0.0);
+        self.real_time + chrono::TimeDelta::microseconds((offset * 1e6).round() as i64)
+    }
+    // Seconds the watch was ahead of real time
+    fn offset_secs(&self) -> f64 {
+        self.watch_time.signed_duration_since(self.reference_time()).num_milliseconds() as f64 / 1000.0
+    }
+    // e.g. "0.012s behind (round trip 0.034s)"
+    fn clock_note(&self) -> String {
+        match (self.clock_offset, self.clock_delay) {
+            (Some(offset), Some(delay)) => format!("{:.3}s {} (round trip {:.3}s)",
+                offset.abs(), if offset >= 0.0 { "behind" } else { "ahead" }, delay),
+            _ => "unknown, taken as correct".to_owned(),
+        }
+    }
+    // The time as it was shown on the watch
+    fn watch_reading(&self) -> DateTime<FixedOffset> {
+        let offset = FixedOffset::east_opt(self.watch_offset).unwrap_or(FixedOffset::east_opt(0).unwrap());
+        self.watch_time.with_timezone(&offset)
+    }
+}
+// A measure shown with its drift in a given unit, since the measure itself is unit-free
+struct MeasureInUnit<'a>(&'a Measure, RateUnit);
+impl std::fmt::Display for MeasureInUnit<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if self.drift.is_none() {
-            write!(f, "  Drift: None\n")?;
-        } else {
-            write!(f, "  Drift: {:+} seconds\n", self.drift.as_ref().unwrap())?;
+        let MeasureInUnit(measure, unit) = *self;
+        match measure.rate {
+            Some(rate) => writeln!(f, "  Drift: {}", format_drift(rate, measure.rate_sd, unit))?,
+            None => writeln!(f, "  Drift: None")?,
+        }
+        if let Some(leg) = &measure.leg {
+            writeln!(f, "  Position: {} (test {})", leg.position.to_str(), leg.test)?;
         }
 
-        if self.measure_start.is_none() {
-            write!(f, "  Start: None\n")?;
-        } else {
-            write!(f, "  Start:\n")?;
-            write!(f, "    Watch: {}\n", self.measure_start.as_ref().unwrap().watch_time)?;
-            write!(f, "    Real : {}\n", self.measure_start.as_ref().unwrap().real_time)?;
+        match &measure.measure_start {
+            Some(start) => {
+                writeln!(f, "  Start:")?;
+                writeln!(f, "    Watch: {}", start.watch_reading())?;
+                writeln!(f, "    Real : {}", start.real_time.with_timezone(&Local))?;
+                writeln!(f, "    Clock: {}", start.clock_note())?;
+            },
+            None => writeln!(f, "  Start: None")?,
         }
 
-        if self.measure_start.is_none() {
-            write!(f, "  End: None\n")?;
-        } else {
-            write!(f, "  End:\n")?;
-            write!(f, "    Watch: {}\n", self.measure_end.as_ref().unwrap().watch_time)?;
-            write!(f, "    Real : {}\n", self.measure_end.as_ref().unwrap().real_time)?;
+        for check in &measure.checks {
+            writeln!(f, "  Check:")?;
+            writeln!(f, "    Watch: {}", check.watch_reading())?;
+            writeln!(f, "    Real : {}", check.real_time.with_timezone(&Local))?;
+            writeln!(f, "    Clock: {}", check.clock_note())?;
+        }
+
+        match &measure.measure_end {
+            Some(end) => {
+                writeln!(f, "  End:")?;
+                writeln!(f, "    Watch: {}", end.watch_reading())?;
+                writeln!(f, "    Real : {}", end.real_time.with_timezone(&Local))?;
+                writeln!(f, "    Clock: {}", end.clock_note())?;
+            },
+            None => writeln!(f, "  End: None")?,
         }
 
         Ok(())
     }
 }
 
-fn get_measure_duration_and_units(start: DateTime<Local>, end: DateTime<Local>) -> (f64, String) {
+fn get_measure_duration_and_units(start: DateTime<Utc>, end: DateTime<Utc>) -> (f64, String) {
     let s = end.signed_duration_since(start).num_seconds();
     let hectodays  = s as f64 / 864.0;
     let mut unit = hectodays.round() / 100.0;
@@ -548,17 +2763,36 @@ fn get_measure_duration_and_units(start: DateTime<Local>, end: DateTime<Local>)
     (unit, units.to_owned())
 }
 
-fn print_markdown_table(mut watches: Vec<Watch>) {
+fn print_markdown_table(mut watches: Vec<Watch>, agg: &AggregateArgs, units: Option<RateUnit>) {
     println!();
-    // Watch Name | # Wears | Drift | Type
+    // Watch Name | # Wears | Drift | Spec | Type
     let name_header = "Watch Name";
     let wears_header = "Num. Wears";
     let drift_header = "Drift";
+    let spec_header = "Spec";
     let type_header = "Type";
+    let spec_cell = |w: &Watch| match w.spec {
+        Some(_) => w.spec_status(agg).to_str(),
+        None => "-",
+    };
 
-    // Sort by number of wears
-    watches.sort_by_key(|w| w.logs.len());
-    watches.reverse();
+    match config().table_sort {
+        TableSort::Wears => {
+            watches.sort_by_key(|w| w.logs.len());
+            watches.reverse();
+        },
+        TableSort::Name => watches.sort_by_key(|w| w.name.to_lowercase()),
+        TableSort::Drift => {
+            // Most accurate first, unmeasured watches last
+            let rate = |w: &Watch| w.rate().map(f64::abs);
+            watches.sort_by(|a, b| match (rate(a), rate(b)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            });
+        },
+    }
 
     // Get widths of the columns - Name
     // let max_name_len = 30;
@@ -580,27 +2814,24 @@ fn print_markdown_table(mut watches: Vec<Watch>) {
     // Get widths of the columns - Drift
     let mut drift_len = drift_header.len();
     for w in &watches {
-        let drift_str = match w.drift() {
-            Some(drift) => {
-                let (unit, units) = w.last_complete_measure().unwrap().measure_duration_and_units();
-                format!("{:+}s/{}, ({} {})", drift, w.movement.unit_str(), unit, units)
-            },
-            None => "??".to_owned(),
-        };
-        drift_len = max(drift_len, drift_str.len());
+        drift_len = max(drift_len, w.table_print_drift(agg, units).chars().count());
     }
 
+    // Get widths of the columns - Spec
+    let spec_len = watches.iter().map(|w| spec_cell(w).len()).fold(spec_header.len(), max);
+
     // Get widths of the columns - Type
-    let type_len = max(type_header.len(), "Mechanical".len());
+    let type_len = watches.iter().map(|w| w.movement.to_str().len()).fold(type_header.len(), max);
 
     // Print the table
     // Header
     let (name_pad_l, name_pad_r) = get_left_right_padding(name_header, name_len);
     let (wears_pad_l, wears_pad_r) = get_left_right_padding(wears_header, wears_len);
     let (drift_pad_l, drift_pad_r) = get_left_right_padding(drift_header, drift_len);
+    let (spec_pad_l, spec_pad_r) = get_left_right_padding(spec_header, spec_len);
     let (type_pad_l, type_pad_r) = get_left_right_padding(type_header, type_len);
     println!(
-        "| {:n_l$}{n}{:n_r$} | {:w_l$}{w}{:w_r$} | {:d_l$}{d}{:d_r$} | {:t_l$}{t}{:t_r$} |", "", "", "", "", "", "", "", "",
+        "| {:n_l$}{n}{:n_r$} | {:w_l$}{w}{:w_r$} | {:d_l$}{d}{:d_r$} | {:s_l$}{s}{:s_r$} | {:t_l$}{t}{:t_r$} |", "", "", "", "", "", "", "", "", "", "",
         n_l = name_pad_l,
         n = name_header,
         n_r = name_pad_r,
@@ -610,14 +2841,18 @@ fn print_markdown_table(mut watches: Vec<Watch>) {
         d_l = drift_pad_l,
         d = drift_header,
         d_r = drift_pad_r,
+        s_l = spec_pad_l,
+        s = spec_header,
+        s_r = spec_pad_r,
         t_l = type_pad_l,
         t = type_header,
         t_r = type_pad_r,
     );
-    println!("|{}|{}|{}|{}|",
+    println!("|{}|{}|{}|{}|{}|",
         "-".repeat(name_len + 2),
         "-".repeat(wears_len + 2),
         "-".repeat(drift_len + 2),
+        "-".repeat(spec_len + 2),
         "-".repeat(type_len + 2),
     );
 
@@ -625,63 +2860,159 @@ fn print_markdown_table(mut watches: Vec<Watch>) {
     for (watch, _) in watches.iter().zip(name_heights.iter()) {
         let n = &watch.table_print_name();
         let t = watch.movement.to_str();
-        let d = match watch.drift() {
-            Some(drift) => {
-                let (val, units) = watch.last_complete_measure().unwrap().measure_duration_and_units();
-                format!("{:+}s/{}, ({} {})", drift, watch.movement.unit_str(), val, units)
-            },
-            None => "??".to_owned(),
-        };
+        let d = &watch.table_print_drift(agg, units);
+        let s = spec_cell(watch);
         let w = format!("{} days", watch.logs.len());
 
-        let (name_pad_l, name_pad_r) = get_left_right_padding(&n, name_len);
-        let (type_pad_l, type_pad_r) = get_left_right_padding(&t, type_len);
-        let (drift_pad_l, drift_pad_r) = get_left_right_padding(&d, drift_len);
+        let (name_pad_l, name_pad_r) = get_left_right_padding(n, name_len);
+        let (type_pad_l, type_pad_r) = get_left_right_padding(t, type_len);
+        let (drift_pad_l, drift_pad_r) = get_left_right_padding(d, drift_len);
+        let (spec_pad_l, spec_pad_r) = get_left_right_padding(s, spec_len);
         let (wears_pad_l, wears_pad_r) = get_left_right_padding(&w, wears_len);
         println!(
-            "| {:n_l$}{n}{:n_r$} | {:w_l$}{w}{:w_r$} | {:d_l$}{d}{:d_r$} | {:t_l$}{t}{:t_r$} |", "", "", "", "", "", "", "", "",
+            "| {:n_l$}{n}{:n_r$} | {:w_l$}{w}{:w_r$} | {:d_l$}{d}{:d_r$} | {:s_l$}{s}{:s_r$} | {:t_l$}{t}{:t_r$} |", "", "", "", "", "", "", "", "", "", "",
             n_l = name_pad_l,
             n_r = name_pad_r,
             w_l = wears_pad_l,
             w_r = wears_pad_r,
             d_l = drift_pad_l,
             d_r = drift_pad_r,
+            s_l = spec_pad_l,
+            s_r = spec_pad_r,
             t_l = type_pad_l,
             t_r = type_pad_r,
         );
     }
-    println!("");
+    println!();
+}
+
+// A unit-free rate and its standard deviation in `unit`, e.g. "+4.2 ± 1.1 s/day", flagged when
+// the drift is smaller than its uncertainty
+fn format_drift(rate: f64, rate_sd: Option<f64>, unit: RateUnit) -> String {
+    let drift = unit.convert(rate);
+    let Some(uncertainty) = rate_sd.map(|sd| unit.convert(sd)) else {
+        return format!("{:+.3} {}", drift, unit.to_str());
+    };
+    // Two significant figures of uncertainty
+    let decimals = match uncertainty > 0.0 {
+        true  => (1 - uncertainty.log10().floor() as i32).clamp(0, 3) as usize,
+        false => 3,
+    };
+    let flag = if uncertainty > drift.abs() { " (within noise)" } else { "" };
+    format!("{:+.d$} ± {:.d$} {}{}", drift, uncertainty, unit.to_str(), flag, d = decimals)
+}
+
+// With three or more readings, show how well they line up
+fn print_fit(m: &Measure) {
+    let Some(fit) = m.fit() else { return };
+    let Some(r_squared) = fit.r_squared else { return };
+    println!("Fit over {} readings, R² = {:.4}", fit.residuals.len(), r_squared);
+    for (reading, residual) in m.readings().iter().zip(&fit.residuals) {
+        println!("  {}  residual {:+.2}s", reading.real_time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), residual);
+    }
+}
+
+// Least-squares line through `points`, as (x, y). Needs at least two distinct x values.
+fn linear_fit(points: &[(f64, f64)]) -> Option<LinearFit> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let syy: f64 = points.iter().map(|p| (p.1 - mean_y).powi(2)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+    let residuals: Vec<f64> = points.iter().map(|p| p.1 - (intercept + slope * p.0)).collect();
+    let ss_res: f64 = residuals.iter().map(|r| r.powi(2)).sum();
+    // Two points always fit exactly, so R² and the scatter only mean something from three on
+    let r_squared = if points.len() > 2 && syy > 0.0 {
+        Some(1.0 - ss_res / syy)
+    } else {
+        None
+    };
+    let residual_sd = if points.len() > 2 {
+        Some((ss_res / (n - 2.0)).sqrt())
+    } else {
+        None
+    };
+    Some(LinearFit {
+        slope,
+        intercept,
+        sxx,
+        residual_sd,
+        r_squared,
+        residuals,
+    })
 }
 
 fn get_left_right_padding(s: &str, len: usize) -> (usize, usize) {
-    let left = (len - s.len()) / 2;
-    let right = len - s.len() - left;
+    let left = (len - s.chars().count()) / 2;
+    let right = len - s.chars().count() - left;
     (left, right)
 }
 
 #[derive(Parser)]
 #[command(name = "wd")]
 struct Cli {
+    /// Path to the watch database file
+    ///
+    /// Resolved in order: this flag, then the WD_DB environment variable, then `db` in the config
+    /// file, then `wd/watches.json` in the platform data directory ($XDG_DATA_HOME or ~/.local/share on
+    /// Linux, ~/Library/Application Support on macOS).
+    #[clap(long, global = true, env = "WD_DB", value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// Fail immediately instead of waiting if another wd process is using the database
+    #[clap(long, global = true)]
+    no_wait: bool,
+
+    /// Include archived watches when listing and selecting
+    #[clap(short, long, global = true)]
+    all: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 #[derive(Subcommand)]
 enum Commands {
+    /// Create an empty database (and its parent directories) at the database path
+    Init {
+    },
+
     /// Create a new watch. May pass in -n <name> and -m <movement>
     New {
         /// Name of the watch
         #[clap(short)]
         name: Option<String>,
-        /// QUARTZ, MECHANICAL, or SMART movement
+        /// Kind of movement
         ///
-        /// Used when calculating how the watch is running to give you Seconds per Day (spd) or
-        /// Seconds per Month (spm)
+        /// Sets the unit the watch's rate is reported in, e.g. seconds per day for mechanicals and
+        /// seconds per month for quartz (see `units` in the config)
         #[clap(short, value_enum)]
         movement: Option<Movement>,
+        /// IANA timezone the watch is kept on, e.g. Europe/London for a GMT watch left on home
+        /// time. Defaults to the system timezone at the time of each reading.
+        #[clap(short = 'z', long, value_name = "ZONE", value_parser = parse_tz)]
+        watch_tz: Option<Tz>,
     },
 
     /// Lists watches in the database. Takes an optional regex pattern to filter.
     Ls {
+        /// Show each watch's ID, for selecting it with @<id>
+        #[clap(long)]
+        ids: bool,
+        #[command(flatten)]
+        agg: AggregateArgs,
+        /// Unit to show rates in, instead of each watch's own
+        #[clap(long, value_enum)]
+        units: Option<RateUnit>,
         /// Regex string used to filter watches
         #[clap(default_value = "", trailing_var_arg = true, allow_hyphen_values = true)]
         search: Vec<String>,
@@ -689,6 +3020,67 @@ enum Commands {
 
     /// Start a measure for the given watch
     Start {
+        #[command(flatten)]
+        reading: Reading,
+        /// Start from the last time the watch was set (see `wd set`), instead of taking a reading
+        #[clap(long, conflicts_with_all = ["watch_time", "real_time", "watch_tz"])]
+        from_set: bool,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Add an intermediate reading to the active measure for the given watch
+    Check {
+        #[command(flatten)]
+        reading: Reading,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Record that the watch was just set, and how far ahead of the reference it was set
+    Set {
+        /// Seconds ahead of the reference the hands were set, e.g. +10s or -2.5s
+        #[clap(long, default_value = "0", allow_hyphen_values = true, value_parser = parse_offset)]
+        offset: f64,
+        /// Reference time the crown was pushed in, as RFC 3339 or `now`. Skips waiting for [Enter].
+        #[clap(long, value_name = "RFC3339|now", value_parser = parse_real_time)]
+        at: Option<RealTime>,
+        /// Abandon the active measure without asking
+        #[clap(short, long)]
+        yes: bool,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Show how the watch's rate has changed over its measures, and when it's projected to
+    /// drift out of tolerance
+    Trend {
+        /// Lower edge of the tolerance band, in the watch's unit. Defaults to `tolerance` in the config.
+        #[clap(long, allow_negative_numbers = true)]
+        min: Option<f64>,
+        /// Upper edge of the tolerance band, in the watch's unit. Defaults to `tolerance` in the config.
+        #[clap(long, allow_negative_numbers = true)]
+        max: Option<f64>,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Work out how far ahead or behind to set a watch so it's closest to correct over a planned wear
+    // `--until` is the end of the wear here, so the aggregate window's end only goes by `--through`
+    #[command(mut_arg("agg_until", |a| a.alias(None)))]
+    SetAdvice {
+        /// End of the wear, as a duration from now (8h, 3d, 1w, 1d12h) or a time ("2024-05-03 18:00")
+        #[clap(long, value_parser = parse_until)]
+        until: DateTime<Utc>,
+        /// When the watch should be closest to correct: midway through the wear, or at its end
+        #[clap(long, value_enum, default_value = "mid")]
+        target: AdviceTarget,
+        #[command(flatten)]
+        agg: AggregateArgs,
         /// Name of the watch
         #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
         name: Vec<String>,
@@ -697,6 +3089,33 @@ enum Commands {
     /// End or Update a measure for the given watch
     #[clap(alias = "stop")]
     End {
+        #[command(flatten)]
+        reading: Reading,
+        /// Unit to show the rate in, instead of the watch's own
+        #[clap(long, value_enum)]
+        units: Option<RateUnit>,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Measure the rate in each position, over several sessions. Run it again to move on to the
+    /// next position.
+    PositionTest {
+        #[command(flatten)]
+        reading: Reading,
+        /// Move on before the configured time in the current position is up
+        #[clap(long)]
+        early: bool,
+        /// Abandon the test in progress and start a new one
+        #[clap(long)]
+        restart: bool,
+        /// Show the results of the latest test without taking a reading
+        #[clap(long, conflicts_with_all = ["early", "restart", "watch_time", "real_time", "watch_tz"])]
+        report: bool,
+        /// Abandon a running measure without asking
+        #[clap(short, long)]
+        yes: bool,
         /// Name of the watch
         #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
         name: Vec<String>,
@@ -716,5 +3135,516 @@ enum Commands {
 
     /// Print all watches to a markdown table
     Print {
+        #[command(flatten)]
+        agg: AggregateArgs,
+        /// Unit to show rates in, instead of each watch's own
+        #[clap(long, value_enum)]
+        units: Option<RateUnit>,
+    },
+
+    /// Rename a watch. May pass in -t <new name>
+    Rename {
+        /// New name for the watch
+        #[clap(short, long)]
+        to: Option<String>,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Change a watch's details
+    Edit {
+        /// Kind of movement
+        #[clap(short, long, value_enum)]
+        movement: Option<Movement>,
+        /// Unit to report the rate in, instead of the movement's default
+        #[clap(short, long, value_enum)]
+        unit: Option<RateUnit>,
+        /// Accuracy spec: a preset (cosc, metas, rolex, gs, gs-special, spring-drive, gs-9f,
+        /// eta-2824, sw200, nh35, 6r, miyota-9, quartz) or a range like "-4/+6 s/day" or "±15 s/month"
+        #[clap(long, allow_hyphen_values = true, value_parser = parse_spec)]
+        spec: Option<Spec>,
+        /// Remove the accuracy spec
+        #[clap(long, conflicts_with = "spec")]
+        no_spec: bool,
+        /// IANA timezone the watch is kept on, e.g. Europe/London for a GMT watch left on home time
+        #[clap(short = 'z', long, value_name = "ZONE", value_parser = parse_tz)]
+        watch_tz: Option<Tz>,
+        /// Go back to reading the watch in the system timezone
+        #[clap(long, conflicts_with = "watch_tz")]
+        no_watch_tz: bool,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Delete a watch and its history, after confirmation
+    Rm {
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Hide a watch from listings and selection, keeping its history
+    Archive {
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Bring back an archived watch
+    Unarchive {
+        /// Name of the watch
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        name: Vec<String>,
+    },
+
+    /// Upgrade the database file to the current schema version. Older files are also upgraded
+    /// in memory whenever they're loaded, and rewritten on the next save.
+    Migrate {
+        /// Only show which migrations would run
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Show or edit user preferences in the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Measure how late you press [Enter] on a tick, so readings can be corrected for it
+    Calibrate {
+        /// Number of presses to average over
+        #[clap(short = 'n', long, default_value = "10", value_parser = clap::value_parser!(u64).range(2..))]
+        trials: u64,
+    },
+}
+// A reading for `start`/`check`/`end`. Anything not given here is prompted for interactively.
+#[derive(clap::Args)]
+struct Reading {
+    /// Time shown on the watch, as HH:MM or HH:MM:SS. Skips the watch time dialog.
+    #[clap(long, value_name = "HH:MM[:SS]", value_parser = parse_watch_time)]
+    watch_time: Option<(NaiveTime, TimePrecision)>,
+    /// Reference time the watch was read at, as RFC 3339 or `now`. Skips waiting for [Enter].
+    #[clap(long, visible_alias = "at", value_name = "RFC3339|now", value_parser = parse_real_time)]
+    real_time: Option<RealTime>,
+    /// IANA timezone the watch is set to for this reading. Defaults to the watch's own timezone,
+    /// then the system's.
+    #[clap(long, value_name = "ZONE", value_parser = parse_tz)]
+    watch_tz: Option<Tz>,
+}
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum AdviceTarget {
+    Mid,
+    End,
+}
+// Which completed measures to summarize, instead of showing only the latest one
+#[derive(clap::Args)]
+struct AggregateArgs {
+    /// Show the rate aggregated over all completed measures
+    #[clap(long)]
+    aggregate: bool,
+    /// Only aggregate the last N measures
+    #[clap(long, value_name = "N", requires = "aggregate")]
+    last: Option<usize>,
+    /// Only aggregate measures that ended on or after this date (YYYY-MM-DD)
+    #[clap(long, value_name = "DATE", requires = "aggregate")]
+    since: Option<NaiveDate>,
+    /// Only aggregate measures that ended on or before this date (YYYY-MM-DD)
+    #[clap(id = "agg_until", long = "through", alias = "until", value_name = "DATE", requires = "aggregate")]
+    until: Option<NaiveDate>,
+}
+impl Commands {
+    fn writes_db(&self) -> bool {
+        !matches!(self,
+            Commands::Ls { .. } |
+            Commands::Print { .. } |
+            Commands::Config { .. } |
+            Commands::Calibrate { .. } |
+            Commands::Trend { .. } |
+            Commands::SetAdvice { .. } |
+            Commands::PositionTest { report: true, .. } |
+            Commands::Migrate { dry_run: true })
+    }
+}
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the effective config, including defaults
+    Show,
+    /// Print a single value, e.g. `units.quartz`
+    Get {
+        key: String,
+    },
+    /// Set a value in the config file, e.g. `wd config set table_sort name`
+    Set {
+        key: String,
+        value: String,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::New_York;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+    fn resolve(real_utc: &str, watch: &str) -> DateTime<Utc> {
+        let real_time = utc(real_utc).with_timezone(&New_York);
+        let time = NaiveTime::parse_from_str(watch, "%H:%M:%S").unwrap();
+        resolve_watch_time(&real_time, time).with_timezone(&Utc)
+    }
+
+    #[test]
+    fn watch_time_same_day() {
+        // 14:32:00 EDT
+        assert_eq!(resolve("2024-06-01T18:32:00Z", "14:32:05"), utc("2024-06-01T18:32:05Z"));
+    }
+
+    #[test]
+    fn watch_time_ahead_across_midnight() {
+        // Enter pressed at 23:59:50 EDT, watch already reads 00:00:00
+        assert_eq!(resolve("2024-06-02T03:59:50Z", "00:00:00"), utc("2024-06-02T04:00:00Z"));
+    }
+
+    #[test]
+    fn watch_time_behind_across_midnight() {
+        // Enter pressed at 00:00:10 EDT, watch still reads 23:59:58 of the previous day
+        assert_eq!(resolve("2024-06-02T04:00:10Z", "23:59:58"), utc("2024-06-02T03:59:58Z"));
+    }
+
+    #[test]
+    fn watch_time_hours_off() {
+        // A watch 11 hours fast is still resolved forwards, not 13 hours back
+        assert_eq!(resolve("2024-06-01T14:00:00Z", "21:00:00"), utc("2024-06-02T01:00:00Z"));
+    }
+
+    #[test]
+    fn watch_time_in_dst_gap() {
+        // 02:00-03:00 doesn't exist on 2024-03-10. At 03:00:20 EDT a watch that hasn't been moved
+        // forward reads 02:00:15, which is 02:00:15 EST.
+        assert_eq!(resolve("2024-03-10T07:00:20Z", "02:00:15"), utc("2024-03-10T07:00:15Z"));
+    }
+
+    #[test]
+    fn watch_time_in_dst_overlap() {
+        // 01:00-02:00 happens twice on 2024-11-03. Pick the occurrence nearest the real time.
+        assert_eq!(resolve("2024-11-03T05:30:05Z", "01:30:00"), utc("2024-11-03T05:30:00Z"));
+        assert_eq!(resolve("2024-11-03T06:30:05Z", "01:30:00"), utc("2024-11-03T06:30:00Z"));
+    }
+
+    #[test]
+    fn cli_args_dont_clash() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    // A reading taken with the system clock unchecked, `ahead` seconds fast
+    fn reading(real_utc: &str, ahead: f64, precision: TimePrecision) -> WatchTimePair {
+        let real_time = utc(real_utc);
+        WatchTimePair {
+            watch_time: real_time + chrono::TimeDelta::milliseconds((ahead * 1000.0) as i64),
+            real_time,
+            watch_offset: 0,
+            system_tz: None,
+            precision,
+            clock_offset: None,
+            clock_delay: None,
+        }
+    }
+    fn measure(readings: Vec<WatchTimePair>) -> Measure {
+        let mut readings = readings.into_iter();
+        let measure_start = readings.next();
+        let mut rest: Vec<WatchTimePair> = readings.collect();
+        let measure_end = rest.pop();
+        Measure { rate: None, rate_sd: None, measure_start, checks: rest, measure_end, leg: None }
+    }
+
+    #[test]
+    fn minute_reading_uncertainty() {
+        CONFIG.get_or_init(Config::default);
+        // Two readings at the watch's :00, 6 hours apart, with the default 0.5s reaction jitter
+        let m = measure(vec![
+            reading("2024-06-01T06:00:00Z", 0.0, TimePrecision::Minute),
+            reading("2024-06-01T12:00:00Z", 0.0, TimePrecision::Minute),
+        ]);
+        let fit = m.fit().unwrap();
+        let sd = RateUnit::Day.convert(m.rate_uncertainty(&fit));
+        // 0.5s / sqrt(6h² / 2), about 2.8 s/day
+        assert!((2.5..3.2).contains(&sd), "{}", sd);
+    }
+
+    #[test]
+    fn fit_three_points() {
+        let fit = linear_fit(&[(0.0, 0.0), (1.0, 1.0), (2.0, 3.0)]).unwrap();
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+        assert!(close(fit.slope, 1.5), "{}", fit.slope);
+        assert!(close(fit.intercept, -1.0 / 6.0), "{}", fit.intercept);
+        // 1 - (1/6) / (14/3)
+        assert!(close(fit.r_squared.unwrap(), 27.0 / 28.0), "{:?}", fit.r_squared);
+        assert!(close(fit.residual_sd.unwrap(), (1.0f64 / 6.0).sqrt()), "{:?}", fit.residual_sd);
+        for (r, expected) in fit.residuals.iter().zip([1.0 / 6.0, -1.0 / 3.0, 1.0 / 6.0]) {
+            assert!(close(*r, expected), "{:?}", fit.residuals);
+        }
+        // Two points fit exactly, so there's no scatter to report
+        let two = linear_fit(&[(0.0, 0.0), (1.0, 1.0)]).unwrap();
+        assert!(two.r_squared.is_none() && two.residual_sd.is_none());
+        assert!(linear_fit(&[(1.0, 0.0), (1.0, 1.0)]).is_none());
+    }
+
+    fn watch(measures: Vec<Measure>) -> Watch {
+        Watch {
+            id: "test".to_owned(),
+            name: "Test".to_owned(),
+            movement: Movement::Automatic,
+            unit: None,
+            spec: None,
+            logs: Vec::new(),
+            measures,
+            sets: Vec::new(),
+            watch_tz: None,
+            archived: false,
+        }
+    }
+    // A completed measure between two times, at a rate in s/day
+    fn measured(start_utc: &str, end_utc: &str, rate: f64) -> Measure {
+        let mut m = measure(vec![
+            reading(start_utc, 0.0, TimePrecision::Second),
+            reading(end_utc, 0.0, TimePrecision::Second),
+        ]);
+        m.rate = Some(RateUnit::Day.to_rate(rate));
+        m
+    }
+
+    #[test]
+    fn summary_weighted_by_length() {
+        let mut leg = measured("2024-06-07T00:00:00Z", "2024-06-07T12:00:00Z", 100.0);
+        leg.leg = Some(PositionLeg { test: 1, position: Position::DialUp });
+        let w = watch(vec![
+            // 1.5, 1 and 0.5 days long, ending far enough apart to filter by date in any timezone
+            measured("2024-05-31T00:00:00Z", "2024-06-01T12:00:00Z", 2.0),
+            measured("2024-06-02T12:00:00Z", "2024-06-03T12:00:00Z", 4.0),
+            measured("2024-06-06T00:00:00Z", "2024-06-06T12:00:00Z", 16.0),
+            // Neither a position test leg nor a running measure counts
+            leg,
+            measure(vec![reading("2024-06-08T00:00:00Z", 0.0, TimePrecision::Second)]),
+        ]);
+        let summarize = |last: Option<usize>, since: Option<&str>| {
+            let agg = AggregateArgs { aggregate: true, last, since: since.map(|s| s.parse().unwrap()), until: None };
+            let s = w.summary(&agg).unwrap();
+            (s.count, RateUnit::Day.convert(s.mean), RateUnit::Day.convert(s.median))
+        };
+        let close = |(count, mean, median): (usize, f64, f64), expected: (usize, f64, f64)| {
+            assert_eq!(count, expected.0);
+            assert!((mean - expected.1).abs() < 1e-9, "mean {}", mean);
+            assert!((median - expected.2).abs() < 1e-9, "median {}", median);
+        };
+        // (2 * 1.5 + 4 * 1 + 16 * 0.5) / 3
+        close(summarize(None, None), (3, 5.0, 4.0));
+        // (4 * 1 + 16 * 0.5) / 1.5
+        close(summarize(Some(2), None), (2, 8.0, 10.0));
+        close(summarize(None, Some("2024-06-05")), (1, 16.0, 16.0));
+        close(summarize(Some(5), Some("2024-06-03")), (2, 8.0, 10.0));
+        let agg = AggregateArgs { aggregate: true, last: None, since: "2024-06-08".parse().ok(), until: None };
+        assert!(w.summary(&agg).is_none());
+    }
+
+    #[test]
+    fn spec_ranges() {
+        let spec = parse_spec("-4/+6 s/day").unwrap();
+        assert_eq!((spec.min, spec.max, spec.name), (-4.0, 6.0, None));
+        assert!(matches!(spec.unit, RateUnit::Day));
+
+        let spec = parse_spec("±15 s/month").unwrap();
+        assert_eq!((spec.min, spec.max), (-15.0, 15.0));
+        assert!(matches!(spec.unit, RateUnit::Month));
+
+        let spec = parse_spec("COSC").unwrap();
+        assert_eq!((spec.min, spec.max, spec.name.as_deref()), (-4.0, 6.0, Some("COSC")));
+        assert!(matches!(spec.unit, RateUnit::Day));
+
+        assert!(parse_spec("+6/-4 s/day").unwrap_err().contains("above its maximum"));
+        assert!(parse_spec("±15 s/fortnight").is_err());
+    }
+
+    #[test]
+    fn spec_status_within_two_sigma() {
+        let spec = parse_spec("-4/+6 s/day").unwrap();
+        let status = |rate: f64, sd: f64| spec.status(RateUnit::Day.to_rate(rate), RateUnit::Day.to_rate(sd));
+        // 2σ either side has to clear the edge to be sure
+        assert!(matches!(status(5.0, 0.49), SpecStatus::Within));
+        assert!(matches!(status(-3.0, 0.49), SpecStatus::Within));
+        assert!(matches!(status(5.0, 0.51), SpecStatus::Unknown));
+        assert!(matches!(status(-3.0, 0.51), SpecStatus::Unknown));
+        assert!(matches!(status(7.0, 0.51), SpecStatus::Unknown));
+        assert!(matches!(status(7.0, 0.49), SpecStatus::Outside));
+        assert!(matches!(status(-5.0, 0.49), SpecStatus::Outside));
+        // Straddling both edges at once
+        assert!(matches!(status(1.0, 3.0), SpecStatus::Unknown));
+
+        let mut w = watch(vec![measured("2024-06-01T00:00:00Z", "2024-06-02T00:00:00Z", 2.0)]);
+        let agg = AggregateArgs { aggregate: false, last: None, since: None, until: None };
+        assert!(matches!(w.spec_status(&agg), SpecStatus::Unknown));
+        w.spec = Some(spec.clone());
+        assert!(matches!(w.spec_status(&agg), SpecStatus::Within));
+    }
+
+    #[test]
+    fn trend_exit_near_flat_slope() {
+        let now = utc("2024-06-01T00:00:00Z");
+        // 6 s/day of headroom at a slope of a hair above zero
+        for slope in [1e-12, 1e-300] {
+            assert_eq!(projected_exit(now, 6.0 / slope), None);
+        }
+        assert_eq!(projected_exit(now, 6.0 / 0.1), Some(utc("2024-07-31T00:00:00Z")));
+    }
+
+    #[test]
+    fn until_durations() {
+        let now = utc("2024-06-01T12:00:00Z");
+        assert_eq!(parse_until_from("8h", now), Ok(utc("2024-06-01T20:00:00Z")));
+        assert_eq!(parse_until_from("1d12h", now), Ok(utc("2024-06-03T00:00:00Z")));
+        assert_eq!(parse_until_from("1.5d", now), Ok(utc("2024-06-03T00:00:00Z")));
+        assert!(parse_until_from("99999999999w", now).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn until_times() {
+        let now = utc("2024-06-01T12:00:00Z");
+        assert_eq!(parse_until_from("2024-06-03T18:00:00+02:00", now), Ok(utc("2024-06-03T16:00:00Z")));
+        let local = Local.with_ymd_and_hms(2024, 6, 3, 18, 0, 0).earliest().unwrap();
+        assert_eq!(parse_until_from("2024-06-03 18:00", now), Ok(local.with_timezone(&Utc)));
+        assert!(parse_until_from("next tuesday", now).is_err());
+    }
+
+    // Loads a database file written at an older schema version, with the default config
+    fn migrate_fixture(contents: &str) -> Database {
+        CONFIG.get_or_init(Config::default);
+        let (db, applied) = try_parse_database(contents).unwrap();
+        assert!(!applied.is_empty());
+        assert_eq!(db.schema_version, SCHEMA_VERSION);
+        db
+    }
+    fn assert_migrated(db: &Database) {
+        let names: Vec<&str> = db.watches.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, ["Speedy", "Quartzy", "Apple"]);
+        let ids: Vec<&str> = db.watches.iter().map(|w| w.id.as_str()).collect();
+        assert!(ids.iter().all(|id| id.len() == 6 && id.chars().all(|c| c.is_ascii_hexdigit())), "{:?}", ids);
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+
+        let speedy = &db.watches[0];
+        assert!(matches!(speedy.movement, Movement::Automatic));
+        let m = &speedy.measures[0];
+        let start = m.measure_start.as_ref().unwrap();
+        assert_eq!(start.real_time, utc("2024-05-01T17:00:00Z"));
+        assert_eq!(start.watch_offset, -7 * 3600);
+        assert_eq!(m.measure_end.as_ref().unwrap().watch_time, utc("2024-05-04T17:00:08.100Z"));
+        // 8.1s over 3 days, worked out from the readings
+        assert!((m.rate.unwrap() - 2.7 / 86400.0).abs() < 1e-12, "{:?}", m.rate);
+        assert!(m.rate_sd.is_some());
+
+        // No start reading to refit from, so the stored s/month drift is converted
+        let quartzy = &db.watches[1];
+        assert!(matches!(quartzy.movement, Movement::Quartz));
+        assert!((quartzy.measures[0].rate.unwrap() - 1.5 / 2_628_000.0).abs() < 1e-15);
+
+        assert!(matches!(db.watches[2].movement, Movement::Smart));
+    }
+
+    #[test]
+    fn migrate_from_bare_array() {
+        let contents = include_str!("../tests/fixtures/v0.json");
+        let db = migrate_fixture(contents);
+        assert_migrated(&db);
+        // IDs are derived from the data, so they don't change until the file is rewritten
+        let again = migrate_fixture(contents);
+        assert!(db.watches.iter().zip(&again.watches).all(|(a, b)| a.id == b.id));
+        // The stored drift that had to be trusted is listed with the migrations, not printed
+        let (_, applied) = try_parse_database(contents).unwrap();
+        assert!(applied.iter().any(|step| step.contains("[Quartzy]")), "{:?}", applied);
+    }
+
+    #[test]
+    fn migrate_from_each_version() {
+        for contents in [
+            include_str!("../tests/fixtures/v1.json"),
+            include_str!("../tests/fixtures/v2.json"),
+            include_str!("../tests/fixtures/v3.json"),
+            include_str!("../tests/fixtures/v4.json"),
+        ] {
+            assert_migrated(&migrate_fixture(contents));
+        }
+        // IDs given from v2 on are kept
+        let db = migrate_fixture(include_str!("../tests/fixtures/v2.json"));
+        assert_eq!(db.watches[0].id, "5f0c1a");
+    }
+
+    #[test]
+    fn newer_schema_refused() {
+        let newer = format!(r#"{{ "schema_version": {}, "watches": [] }}"#, SCHEMA_VERSION + 1);
+        assert!(matches!(try_parse_database(&newer), Err(DatabaseError::TooNew(v)) if v == SCHEMA_VERSION + 1));
+    }
+
+    // Answers one SNTP request on a local port, `offset` seconds ahead of the system clock
+    fn sntp_stand_in(offset: f64) -> String {
+        sntp_stand_in_with(offset, |_| {})
+    }
+    // Same, with `edit` applied to the reply before it's sent
+    fn sntp_stand_in_with(offset: f64, edit: impl FnOnce(&mut [u8; 48]) + Send + 'static) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let mut request = [0u8; 48];
+            let (_, client) = socket.recv_from(&mut request).unwrap();
+            let now = Utc::now() + chrono::TimeDelta::microseconds((offset * 1e6) as i64);
+            let mut response = [0u8; 48];
+            // Version 4, server mode, stratum 1
+            response[0] = 0b00_100_100;
+            response[1] = 1;
+            response[24..32].copy_from_slice(&request[40..48]);
+            response[32..40].copy_from_slice(&to_ntp_timestamp(now).to_be_bytes());
+            response[40..48].copy_from_slice(&to_ntp_timestamp(now).to_be_bytes());
+            edit(&mut response);
+            socket.send_to(&response, client).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn sntp_offset_from_stand_in() {
+        let addr = sntp_stand_in(2.5);
+        let (offset, delay) = sntp_query(&addr, std::time::Duration::from_secs(2)).unwrap();
+        assert!((offset - 2.5).abs() < 0.05, "offset {}", offset);
+        assert!((0.0..0.05).contains(&delay), "delay {}", delay);
+    }
+
+    #[test]
+    fn sntp_rejects_bad_replies() {
+        let edits: [fn(&mut [u8; 48]); 4] = [
+            // Leap indicator 3: unsynchronized
+            |r| r[0] |= 0b11_000_000,
+            // Stratum 0: kiss-o'-death
+            |r| r[1] = 0,
+            // Client mode instead of server
+            |r| r[0] = 0b00_100_011,
+            |r| r[40..48].fill(0),
+        ];
+        for edit in edits {
+            let addr = sntp_stand_in_with(0.0, edit);
+            assert!(sntp_query(&addr, std::time::Duration::from_secs(2)).is_err());
+        }
+    }
+
+    #[test]
+    fn sntp_unreachable_times_out() {
+        // Bound, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        assert!(sntp_query(&addr, std::time::Duration::from_millis(100)).is_err());
+    }
+}